@@ -4,23 +4,87 @@ defmodule Rambo do
              |> Enum.drop(2)
              |> Enum.join("\n")
 
-  defstruct status: nil, out: "", err: ""
+  defstruct status: nil,
+            out: "",
+            err: "",
+            killed_by: nil,
+            signal: nil,
+            core_dumped: false,
+            limit_exceeded: nil,
+            usage: nil,
+            statuses: nil,
+            truncated: false
 
   @type t :: %__MODULE__{
           status: integer(),
           out: String.t(),
-          err: String.t()
+          err: String.t(),
+          killed_by: :sigterm | :sigkill | nil,
+          signal: pos_integer() | nil,
+          core_dumped: boolean(),
+          limit_exceeded: :cpu | :fsize | nil,
+          usage: usage() | nil,
+          statuses: [integer() | {:signal, pos_integer()} | nil] | nil,
+          truncated: boolean()
         }
   @type args :: String.t() | [iodata()] | nil
-  @type result :: {:ok, t()} | {:error, t() | String.t()}
+  @type stage :: String.t() | {String.t(), args()} | {String.t(), args(), Keyword.t()}
+  @type result ::
+          {:ok, t()}
+          | {:error, t() | Rambo.SpawnError.t() | String.t() | {:protocol, String.t()}}
+          | {:timeout, t()}
+          | {:output_limit, t()}
+  @type usage :: %{
+          user_time: non_neg_integer(),
+          system_time: non_neg_integer(),
+          max_rss: non_neg_integer(),
+          minor_faults: non_neg_integer(),
+          major_faults: non_neg_integer(),
+          voluntary_switches: non_neg_integer(),
+          involuntary_switches: non_neg_integer(),
+          wall_time: non_neg_integer()
+        }
+  @type limit :: :cpu | :fsize | :data | :stack | :core | :nofile | :as | :nproc
+  @type signal ::
+          :sighup
+          | :sigint
+          | :sigquit
+          | :sigkill
+          | :sigusr1
+          | :sigusr2
+          | :sigalrm
+          | :sigterm
+          | :sigcont
+          | :sigstop
+          | :sigtstp
+          | :sigwinch
+
+  @signals [
+    :sighup,
+    :sigint,
+    :sigquit,
+    :sigkill,
+    :sigusr1,
+    :sigusr2,
+    :sigalrm,
+    :sigterm,
+    :sigcont,
+    :sigstop,
+    :sigtstp,
+    :sigwinch
+  ]
 
   alias __MODULE__
+  import Bitwise
 
   @doc """
   Stop by killing your command.
 
-  Pass the `pid` of the process that called `run/1`. That process will return
-  with `{:killed, %Rambo{}}` with results accumulated thus far.
+  Pass the `pid` of the process that called `run/1`, or the handle returned by
+  `start/3`. That process will return with `{:killed, %Rambo{}}` with results
+  accumulated thus far. `:killed_by`
+  tells whether your command exited after `SIGTERM` during the `:kill_grace`
+  period or had to be killed with `SIGKILL`.
 
   ## Example
 
@@ -29,7 +93,7 @@ defmodule Rambo do
       ...> end)
       iex> Rambo.kill(task.pid)
       iex> Task.await(task)
-      {:killed, %Rambo{status: nil}}
+      {:killed, %Rambo{status: nil, killed_by: :sigkill}}
 
   """
   @spec kill(pid()) :: {:killed, t()}
@@ -37,13 +101,303 @@ defmodule Rambo do
     send(pid, :kill)
   end
 
+  @doc """
+  Send `signal` to your command.
+
+  Pass the `pid` of the process that called `run/1`, or the handle returned by
+  `start/3`. Unlike `kill/1`, output
+  is still collected until your command exits, so it can clean up first.
+  Signals are ignored on Windows.
+
+  ## Example
+
+      iex> task = Task.async(fn ->
+      ...>   Rambo.run("sleep", "10")
+      ...> end)
+      iex> Rambo.signal(task.pid, :sigterm)
+      iex> Task.await(task)
+      {:error, %Rambo{status: nil, signal: 15}}
+
+  """
+  @spec signal(pid(), signal()) :: {Rambo, {:signal, signal()}}
+  def signal(pid, signal) when signal in @signals do
+    control(pid, {:signal, signal})
+  end
+
+  def signal(_pid, signal) do
+    raise ArgumentError, message: "unknown signal '#{inspect(signal)}'"
+  end
+
+  @doc """
+  Resize the pseudo-terminal of your command.
+
+  Pass the `pid` of the process that called `run/3` with the `:pty` option, or
+  the handle returned by `start/3`.
+  Your command receives `SIGWINCH` so it can redraw itself.
+
+  ## Example
+
+      iex> script = "trap 'stty size; exit' WINCH; while true; do sleep 0.1; done"
+      iex> task = Task.async(fn ->
+      ...>   Rambo.run("/bin/sh", ["-c", script], pty: true)
+      ...> end)
+      iex> Process.sleep(500)
+      iex> Rambo.resize(task.pid, 50, 132)
+      iex> Task.await(task)
+      {:ok, %Rambo{status: 0, out: "50 132\r\n"}}
+
+  """
+  @spec resize(pid(), pos_integer(), pos_integer()) ::
+          {Rambo, {:resize, pos_integer(), pos_integer()}}
+  def resize(pid, rows, cols) do
+    check_size(rows, cols)
+    control(pid, {:resize, rows, cols})
+  end
+
+  @doc """
+  Write `data` to the standard input of your command.
+
+  Pass the handle returned by `start/3`, or the `pid` of the process that
+  called `run/3` with `in: :open`.
+  """
+  @spec write(pid(), iodata()) :: {Rambo, {:stdin, iodata()}}
+  def write(pid, data) do
+    control(pid, {:stdin, data})
+  end
+
+  @doc """
+  Close the standard input of your command, which sends EOF.
+
+  Pass the handle returned by `start/3`, or the `pid` of the process that
+  called `run/3` with `in: :open`.
+  """
+  @spec close_stdin(pid()) :: {Rambo, :close_stdin}
+  def close_stdin(pid) do
+    control(pid, :close_stdin)
+  end
+
+  @doc """
+  Credit back `output` your process has handled, so `rambo` may send more.
+
+  Pass the handle returned by `start/3` with the `:credit` option, and each
+  output or error received from it once you are done with it.
+  """
+  @spec ack(pid(), binary()) :: {Rambo, {:ack, non_neg_integer()}}
+  def ack(pid, output) do
+    control(pid, {:ack, byte_size(output)})
+  end
+
+  # tagged so the process running the command leaves its other messages alone
+  defp control(pid, message) do
+    send(pid, {Rambo, message})
+  end
+
+  @doc ~S"""
+  Starts `command` with arguments or options without waiting for it to exit.
+
+  Unlike `run/3`, the calling process is free to do other work, so this works
+  from a GenServer. Returns `{:ok, handle}` once `rambo` is ready, or
+  `{:error, reason}` if it cannot run commands, and raises `ArgumentError` on
+  invalid options. It then sends your process `{:rambo, handle, {:stdout,
+  output}}` and `{:rambo, handle, {:stderr, error}}` messages as output
+  arrives, or `{:rambo, handle, {:stdout, output, %{seq: seq, at: at}}}` with
+  `:timestamps`. Finally it sends either `{:rambo, handle, {:exit, status}}`,
+  where `status` is `{:signal, signal}` if your command was terminated by a
+  signal, or `{:killed, %Rambo{}}`, `{:timeout, %Rambo{}}` or
+  `{:output_limit, %Rambo{}}` if it was stopped, or `{:rambo, handle,
+  {:error, reason}}` if it could not run.
+
+  Output is not collected. Standard input stays open for `write/2` until
+  `close_stdin/1`, unless `:in` is given. Pass `handle` to `kill/1`,
+  `signal/2` and `resize/3` as well. The handle is linked to your process,
+  so your command is killed if your process exits. Takes the same options as
+  `run/3` except `:log`. With `:credit`, pass each output you handle to
+  `ack/2`, otherwise your command stops once the credit runs out.
+
+  ## Examples
+
+      iex> {:ok, handle} = Rambo.start("cat")
+      iex> Rambo.write(handle, "rambo")
+      iex> Rambo.close_stdin(handle)
+      iex> receive do: ({:rambo, ^handle, {:stdout, out}} -> out)
+      "rambo"
+      iex> receive do: ({:rambo, ^handle, {:exit, status}} -> status)
+      0
+
+  """
+  @spec start(command :: String.t(), args_or_opts :: args() | Keyword.t()) ::
+          {:ok, pid()} | {:error, term()}
+  def start(command, args_or_opts \\ []) do
+    if Keyword.keyword?(args_or_opts) do
+      start(command, nil, args_or_opts)
+    else
+      start(command, args_or_opts, [])
+    end
+  end
+
+  @spec start(command :: String.t(), args :: args(), opts :: Keyword.t()) ::
+          {:ok, pid()} | {:error, term()}
+  def start(command, args, opts) when is_binary(command) and byte_size(command) > 0 do
+    owner = self()
+    opts = Keyword.put_new(opts, :in, :open)
+    # the handle is linked, so invalid options would crash the caller later
+    check_options(opts)
+
+    handle =
+      spawn_link(fn ->
+        handle = self()
+
+        opts =
+          opts
+          |> Keyword.put(:log, &send(owner, {:rambo, handle, &1}))
+          |> Keyword.put(:started, fn -> send(owner, {:rambo, handle, :started}) end)
+
+        result = run_stages([{command, args, []}], opts, %Rambo{out: nil, err: nil})
+        send(owner, {:rambo, handle, exit_event(result)})
+      end)
+
+    # returns once rambo has agreed to run the command
+    receive do
+      {:rambo, ^handle, :started} -> {:ok, handle}
+      {:rambo, ^handle, {:error, reason}} -> {:error, reason}
+    end
+  end
+
+  def start(command, _args, _opts) do
+    raise ArgumentError, message: "invalid command '#{inspect(command)}'"
+  end
+
+  defp exit_event({reason, %Rambo{} = result})
+       when reason in [:killed, :timeout, :output_limit] do
+    {:exit, {reason, result}}
+  end
+
+  defp exit_event({_reason, %Rambo{status: nil, signal: signal}}) when is_integer(signal) do
+    {:exit, {:signal, signal}}
+  end
+
+  defp exit_event({_reason, %Rambo{status: status}}), do: {:exit, status}
+  defp exit_event({:error, reason}), do: {:error, reason}
+
+  # chunks of output rambo may send a stream ahead of its consumer
+  @stream_credit [frames: 16]
+
+  @doc ~S"""
+  Streams the output of `command` lazily.
+
+  Returns a `Stream` of chunks of standard output, or of lines with `framing:
+  :line`. Your command starts when the stream is run and is killed if the
+  stream halts before it exits. `rambo` reads only 16 chunks ahead of what
+  the stream has consumed, or as much as `:credit` says, after which your
+  command blocks writing output, so memory stays bounded however much your
+  command outputs.
+
+  Standard error is logged as `:log` says. Raises `Rambo.SpawnError` if your
+  command fails to start, or `RuntimeError` if it exits with a non-zero
+  status, is killed or cannot run. Takes the same options as `run/3`, and
+  standard input is closed unless `:in` is given.
+
+  ## Examples
+
+      iex> Rambo.stream("printf", "a\\nb\\nc\\n", framing: :line) |> Enum.to_list()
+      ["a\n", "b\n", "c\n"]
+
+      iex> Rambo.stream("yes", framing: :line) |> Enum.take(2)
+      ["y\n", "y\n"]
+
+  """
+  @spec stream(command :: String.t(), args_or_opts :: args() | Keyword.t()) :: Enumerable.t()
+  def stream(command, args_or_opts \\ []) do
+    if Keyword.keyword?(args_or_opts) do
+      stream(command, nil, args_or_opts)
+    else
+      stream(command, args_or_opts, [])
+    end
+  end
+
+  @spec stream(command :: String.t(), args :: args(), opts :: Keyword.t()) :: Enumerable.t()
+  def stream(command, args, opts) do
+    {log, opts} = Keyword.pop(opts, :log, :stderr)
+
+    opts =
+      opts
+      |> Keyword.put_new(:in, nil)
+      |> Keyword.put_new(:credit, @stream_credit)
+
+    Stream.resource(
+      fn ->
+        case start(command, args, opts) do
+          {:ok, handle} -> {handle, command, log_to(log)}
+          {:error, reason} -> raise RuntimeError, message: reason
+        end
+      end,
+      &next_output/1,
+      &stop_stream/1
+    )
+  end
+
+  # each chunk received is acked, keeping the same credit in flight
+  defp next_output({handle, command, log} = stream) do
+    receive do
+      {:rambo, ^handle, {:exit, 0}} ->
+        {:halt, stream}
+
+      {:rambo, ^handle, {:exit, status}} ->
+        raise RuntimeError, message: exit_message(command, status)
+
+      {:rambo, ^handle, {:error, %Rambo.SpawnError{} = error}} ->
+        raise error
+
+      {:rambo, ^handle, {:error, {:protocol, reason}}} ->
+        raise RuntimeError, message: "rambo did not understand a message: #{reason}"
+
+      {:rambo, ^handle, {:error, reason}} ->
+        raise RuntimeError, message: reason
+
+      {:rambo, ^handle, {to, output}} ->
+        ack(handle, output)
+        output = maybe_log(to, output, log)
+        {if(to == :stdout, do: [output], else: []), stream}
+
+      {:rambo, ^handle, {to, output, _stamp}} ->
+        ack(handle, output)
+        output = maybe_log(to, output, log)
+        {if(to == :stdout, do: [output], else: []), stream}
+    end
+  end
+
+  defp exit_message(command, {:signal, signal}), do: "#{command} exited with signal #{signal}"
+  defp exit_message(command, {:killed, _result}), do: "#{command} was killed"
+  defp exit_message(command, {:timeout, _result}), do: "#{command} timed out"
+  defp exit_message(command, {:output_limit, _result}), do: "#{command} output too much"
+  defp exit_message(command, status), do: "#{command} exited with status #{status}"
+
+  # events already sent are flushed, up to the exit of the process running
+  # the command
+  defp stop_stream({handle, _command, _log}) do
+    monitor = Process.monitor(handle)
+    kill(handle)
+    flush_events(handle, monitor)
+  end
+
+  defp flush_events(handle, monitor) do
+    receive do
+      {:rambo, ^handle, _event} -> flush_events(handle, monitor)
+      {:DOWN, ^monitor, :process, ^handle, _reason} -> :ok
+    end
+  end
+
   @doc ~S"""
   Runs `command`.
 
   Executes the `command` and returns `{:ok, %Rambo{}}` or `{:error, reason}`.
-  `reason` is a string if the child process failed to start, or a `%Rambo{}`
-  struct if the child process started successfully but exited with a non-zero
-  status.
+  `reason` is a `%Rambo.SpawnError{}` if the child process failed to start, or
+  a `%Rambo{}` struct if the child process started successfully but exited with
+  a non-zero status. If it was terminated by a signal instead, `:status` is `nil` and
+  `:signal` holds the signal number. `{:error, {:protocol, reason}}` means the
+  shim received a message it could not understand. Options that are not
+  supported on your platform, or by an outdated shim, return `{:error, reason}`
+  without running your command.
 
   Multiple calls can be chained together with the `|>` pipe operator to
   simulate Unix pipes.
@@ -70,6 +424,8 @@ defmodule Rambo do
   Runs `command` with arguments or options.
 
   Arguments can be a string or list of strings. See `run/3` for options.
+  Arguments, environment variables and paths are passed as raw bytes, so they
+  need not be valid UTF-8 except on Windows.
 
   ## Examples
 
@@ -82,6 +438,9 @@ defmodule Rambo do
       iex> Rambo.run("cat", in: "john")
       {:ok, %Rambo{out: "john", status: 0}}
 
+      iex> Rambo.run("cat", in: Stream.map(["jo", "hn"], & &1))
+      {:ok, %Rambo{out: "john", status: 0}}
+
   """
   @spec run(command :: String.t() | result(), args_or_opts :: args() | Keyword.t()) :: result()
   def run(command, args_or_opts) do
@@ -107,7 +466,10 @@ defmodule Rambo do
 
   ## Options
 
-    * `:in` - pipe iodata as standard input
+    * `:in` - pipe iodata as standard input. Any other enumerable, such as a
+    `Stream`, is streamed to the command chunk by chunk, at most 16 chunks
+    ahead of what has been written to it. `:open` keeps it open for
+    `write/2` until `close_stdin/1`.
     * `:cd` - the directory to run the command in
     * `:env` - map or list of tuples containing environment key-value as strings
     * `:log` - stream standard output or standard error to console or a
@@ -115,8 +477,67 @@ defmodule Rambo do
     neither, or a function with one arity. If a function is given, it will be
     passed `{:stdout, output}` or `{:stderr, error}` tuples. Defaults to
     `:stderr`.
-    * `:timeout` - kills command after timeout in milliseconds. Defaults to no
-    timeout.
+    * `:timeout` - kills command after timeout in milliseconds and returns
+    `{:timeout, %Rambo{}}`. Defaults to no timeout.
+    * `:kill_grace` - when killed or timed out, send `SIGTERM` first and wait
+    up to this many milliseconds for your command to exit before sending
+    `SIGKILL`. Output is still collected while waiting. Defaults to killing
+    immediately.
+    * `:pty` - run your command in a pseudo-terminal, for programs that
+    behave differently when attached to a terminal. May be `true` or a
+    `{rows, cols}` window size, defaults to `{24, 80}` when `true`. Standard
+    error is merged into standard output and closing standard input sends
+    `^D`. Not supported on Windows.
+    * `:subreaper` - on Linux, also kill descendants that left the process
+    group of your command, such as daemons. Defaults to `false`.
+    * `:limits` - keyword list of resource limits set with `setrlimit`, such
+    as `[cpu: 10, as: 512_000_000, nofile: 256]`. Keys are `:cpu` (seconds),
+    `:fsize`, `:data`, `:stack`, `:core`, `:as` (bytes), `:nofile` and
+    `:nproc`. If your command is killed for exceeding `:cpu` or `:fsize`,
+    `:limit_exceeded` says which. Other limits make system calls fail
+    instead. Not supported on Windows.
+    * `:usage` - when `true`, `:usage` holds the resources used by your
+    command and its descendants: `:user_time`, `:system_time` and `:wall_time`
+    in microseconds, `:max_rss` in bytes, `:minor_faults`, `:major_faults`,
+    `:voluntary_switches` and `:involuntary_switches`. Only `:wall_time` is
+    measured on Windows. Defaults to `false`.
+    * `:uid` - user ID to run your command as, such as `65534` to drop root
+    privileges. Not supported on Windows.
+    * `:gid` - group ID to run your command as. Not supported on Windows.
+    * `:groups` - list of supplementary group IDs for your command. When
+    running as root with only `:uid`, supplementary groups are dropped. Not
+    supported on Windows.
+    * `:umask` - file mode creation mask of your command, such as `0o027`.
+    Not supported on Windows.
+    * `:stderr` - `:separate` to capture standard error in `:err`, `:merge`
+    to send it to standard output like `2>&1` so both keep their order in
+    `:out`, or `:discard` to throw it away. In a pipeline, this applies to
+    every stage. Merging is not supported on Windows. Defaults to
+    `:separate`.
+    * `:timestamps` - when `true`, a `:log` function is passed
+    `{:stdout, output, %{seq: seq, at: at}}` or `{:stderr, error, %{seq: seq,
+    at: at}}` tuples instead, where `seq` orders chunks across both streams
+    and `at` is the nanoseconds since your command was spawned. Defaults to
+    `false`.
+    * `:framing` - `:raw` to receive output as soon as it is read, or `:line`
+    to receive each stream one complete line at a time, so `:log` never sees
+    partial lines. Lines longer than 65536 bytes are split, or pass
+    `{:line, max_length}` to choose another length. An unterminated last
+    line is sent when the stream closes. Defaults to `:raw`.
+    * `:max_out` - most bytes of standard output to collect. Beyond it, output
+    is read but dropped and `:truncated` is `true`. Pass `{max, :kill}` to
+    kill your command instead and return `{:output_limit, %Rambo{}}`.
+    Defaults to no limit.
+    * `:max_err` - same as `:max_out` for standard error, shared by every
+    stage of a pipeline.
+    * `:server` - run your command as a request of a `Rambo.Server` instead
+    of starting `rambo` just for it.
+    * `:credit` - how much output `rambo` may send ahead of your process, as
+    `[frames: count]`, `[bytes: count]` or both. Once used up, `rambo` stops
+    reading output until your process has handled some, so your command
+    blocks writing instead of flooding your mailbox, such as when `:log` is
+    slow. A chunk is sent whole while any bytes are left. Defaults to no
+    limit.
 
   ## Examples
 
@@ -144,43 +565,100 @@ defmodule Rambo do
         {:error, reason}
 
       command when byte_size(command) > 0 ->
-        {stdin, opts} = Keyword.pop(opts, :in)
-        {envs, opts} = Keyword.pop(opts, :env)
-        {current_dir, opts} = Keyword.pop(opts, :cd)
-        {log, opts} = Keyword.pop(opts, :log, :stderr)
-        {timeout, _opts} = Keyword.pop(opts, :timeout)
-
-        log =
-          case log do
-            log when is_function(log) -> log
-            true -> [:stdout, :stderr]
-            log -> [log]
-          end
-
-        rambo = Mix.Tasks.Compile.Rambo.find_rambo()
-        port = Port.open({:spawn, rambo}, [:binary, :exit_status, {:packet, 4}])
-        send_command(port, command)
-
-        if args, do: send_arguments(port, args)
-        if stdin, do: send_stdin(port, stdin)
-        if envs, do: send_envs(port, envs)
-        if current_dir, do: send_current_dir(port, current_dir)
-
-        if is_integer(timeout) do
-          Process.send_after(self(), :kill, timeout)
-        end
-
-        run_command(port)
-
-        port
-        |> receive_result(%Rambo{}, log)
-        |> output_to_binary()
+        run_stages([{command, args, []}], opts)
 
       command ->
         raise ArgumentError, message: "invalid command '#{inspect(command)}'"
     end
   end
 
+  @doc ~S"""
+  Runs `stages` as a pipeline, connecting the standard output of each stage
+  to the standard input of the next with OS pipes.
+
+  Stages run concurrently in a single `rambo` process. Each stage is a
+  command, a `{command, args}` tuple or a `{command, args, opts}` tuple where
+  `opts` may contain `:env` and `:cd` for that stage only. `:in` feeds the
+  first stage, `:out` holds the output of the last stage and `:err` the
+  standard error of every stage.
+
+  `:status` is the exit status of the last stage and `:statuses` holds the
+  exit status of every stage in order, `{:signal, signal}` for stages killed
+  by a signal or `nil` when unknown. Takes the same options as `run/3`
+  except `:pty`, and also:
+
+    * `:pipefail` - when `true`, `:status` is the exit status of the
+    rightmost stage that failed, or `0` if every stage succeeded. Defaults to
+    `false`.
+
+  ## Examples
+
+      iex> Rambo.pipeline([{"echo", "rambo"}, {"tr", ["a-z", "A-Z"]}])
+      {:ok, %Rambo{out: "RAMBO\n", status: 0, statuses: [0, 0]}}
+
+  """
+  @spec pipeline(stages :: [stage()], opts :: Keyword.t()) :: result()
+  def pipeline(stages, opts \\ []) when is_list(stages) and stages != [] do
+    stages
+    |> Enum.map(&stage/1)
+    |> run_stages(opts)
+  end
+
+  # raises before anything is sent, so no command runs with options it cannot
+  # take as given
+  defp check_options(opts) do
+    check_milliseconds(:timeout, opts[:timeout])
+    check_milliseconds(:kill_grace, opts[:kill_grace])
+    if opts[:uid], do: check_integer(:uid, opts[:uid], 0..0xFFFF_FFFF)
+    if opts[:gid], do: check_integer(:gid, opts[:gid], 0..0xFFFF_FFFF)
+    for group <- opts[:groups] || [], do: check_integer(:groups, group, 0..0xFFFF_FFFF)
+    if opts[:umask], do: check_integer(:umask, opts[:umask], 0..0o777)
+
+    with {rows, cols} <- opts[:pty], do: check_size(rows, cols)
+
+    with {:line, max_length} <- opts[:framing] do
+      check_integer("line length", max_length, 1..0xFFFF_FFFF)
+    end
+
+    # no credit at all would stall the command for good
+    for {unit, amount} <- opts[:credit] || [] do
+      check_integer("credit #{unit}", amount, 1..0xFFFF_FFFF)
+    end
+  end
+
+  # rambo reads terminal sizes as 16 bits, and a zero size is no terminal
+  defp check_size(rows, cols) do
+    check_integer(:rows, rows, 1..0xFFFF)
+    check_integer(:cols, cols, 1..0xFFFF)
+  end
+
+  # rambo reads milliseconds as 32 bits, so larger values would wrap around
+  defp check_milliseconds(option, ms) when is_integer(ms) and ms not in 0..0xFFFF_FFFF do
+    raise ArgumentError, message: "invalid #{option} '#{ms}', must be 0..4294967295 ms"
+  end
+
+  defp check_milliseconds(_option, _ms), do: :ok
+
+  # rambo reads other numbers at a fixed width too, where out of range values
+  # would wrap around just as silently
+  defp check_integer(_option, value, range) when is_integer(value) and value in range, do: :ok
+
+  defp check_integer(option, value, range) do
+    raise ArgumentError,
+      message: "invalid #{option} '#{inspect(value)}', must be #{inspect(range)}"
+  end
+
+  defp stage({command, args, opts}) when is_binary(command) and byte_size(command) > 0 do
+    {command, args, opts}
+  end
+
+  defp stage({command, args}), do: stage({command, args, []})
+  defp stage(command) when is_binary(command), do: stage({command, nil, []})
+
+  defp stage(stage) do
+    raise ArgumentError, message: "invalid stage '#{inspect(stage)}'"
+  end
+
   @doc false
   @spec run(result :: result(), command :: String.t(), args :: args(), opts :: Keyword.t()) ::
           result()
@@ -191,6 +669,127 @@ defmodule Rambo do
     end
   end
 
+  defp run_stages(stages, opts, result \\ %Rambo{}) do
+    check_options(opts)
+
+    {stdin, opts} = Keyword.pop(opts, :in)
+    {envs, opts} = Keyword.pop(opts, :env)
+    {current_dir, opts} = Keyword.pop(opts, :cd)
+    {pipefail, opts} = Keyword.pop(opts, :pipefail, false)
+    {stderr, opts} = Keyword.pop(opts, :stderr, :separate)
+    {log, opts} = Keyword.pop(opts, :log, :stderr)
+    {started, opts} = Keyword.pop(opts, :started)
+    {timestamps, opts} = Keyword.pop(opts, :timestamps, false)
+    {framing, opts} = Keyword.pop(opts, :framing, :raw)
+    {max_out, opts} = Keyword.pop(opts, :max_out)
+    {max_err, opts} = Keyword.pop(opts, :max_err)
+    {server, opts} = Keyword.pop(opts, :server)
+    {credit, opts} = Keyword.pop(opts, :credit)
+    {timeout, opts} = Keyword.pop(opts, :timeout)
+    {kill_grace, opts} = Keyword.pop(opts, :kill_grace)
+    {subreaper, opts} = Keyword.pop(opts, :subreaper, false)
+    {pty, opts} = Keyword.pop(opts, :pty, false)
+    {limits, opts} = Keyword.pop(opts, :limits)
+    {usage, opts} = Keyword.pop(opts, :usage, false)
+    {uid, opts} = Keyword.pop(opts, :uid)
+    {gid, opts} = Keyword.pop(opts, :gid)
+    {groups, opts} = Keyword.pop(opts, :groups)
+    {umask, _opts} = Keyword.pop(opts, :umask)
+
+    {stdin, stream} =
+      if is_nil(stdin) or is_binary(stdin) or is_list(stdin) do
+        {stdin, nil}
+      else
+        {nil, stdin}
+      end
+
+    log = log_to(log)
+
+    # stamps are stripped from output before it is collected
+    log = if timestamps, do: {:timestamps, log}, else: log
+
+    features = [
+      stream_stdin: stream,
+      stdin_ack: stream,
+      kill_grace: kill_grace,
+      timeout: is_integer(timeout),
+      pty: pty,
+      limits: limits,
+      usage: usage,
+      credentials: uid || gid || groups || umask,
+      pipeline: length(stages) > 1 or pipefail,
+      stderr_mode: stderr != :separate,
+      timestamps: timestamps,
+      line_framing: framing != :raw,
+      max_output: max_out || max_err,
+      credit: credit
+    ]
+
+    rambo = Mix.Tasks.Compile.Rambo.find_rambo()
+
+    port =
+      if server do
+        Rambo.Server.checkout(server)
+      else
+        Port.open({:spawn, rambo}, [:binary, :exit_status, {:packet, 4}, owner_env()])
+      end
+
+    with {:ok, capabilities} <- handshake(port, rambo),
+         :ok <- check_capabilities(port, capabilities, features) do
+      if started, do: started.()
+      send_stages(port, stages, envs, current_dir)
+
+      if stdin, do: send_stdin(port, stdin)
+      if stream, do: open_stdin(port)
+      if kill_grace, do: send_kill_grace(port, kill_grace)
+      # only Linux has subreapers, elsewhere the process group is killed as usual
+      if subreaper and :subreaper in capabilities, do: send_subreaper(port)
+      if is_integer(timeout), do: send_timeout(port, timeout)
+      if pty, do: send_pty(port, pty)
+      if limits, do: send_limits(port, limits)
+      if uid, do: send_uid(port, uid)
+      if gid, do: send_gid(port, gid)
+      if groups, do: send_groups(port, groups)
+      if umask, do: send_umask(port, umask)
+      if pipefail, do: send_pipefail(port)
+      if stderr != :separate, do: send_stderr_mode(port, stderr)
+      if timestamps, do: send_timestamps(port)
+      if framing != :raw, do: send_line_framing(port, framing)
+      if max_out, do: send_max_output(port, "stdout", max_out)
+      if max_err, do: send_max_output(port, "stderr", max_err)
+      for {unit, amount} <- credit || [], do: send_credit(port, unit, amount)
+
+      run_command(port)
+      streamer = if stream, do: stream_stdin(port, stream)
+
+      result =
+        port
+        |> receive_result(result, log, kill_grace, credit, streamer)
+        |> output_to_binary()
+        |> maybe_usage(usage)
+
+      if streamer, do: stop_streaming(streamer)
+      result
+    end
+  end
+
+  # stages share the environment and directory of the whole pipeline
+  defp send_stages(port, stages, envs, current_dir) do
+    stages
+    |> Enum.with_index()
+    |> Enum.each(fn {{command, args, opts}, index} ->
+      if index > 0, do: send_pipe(port)
+      send_command(port, command)
+
+      envs = Enum.concat(envs || [], Keyword.get(opts, :env, []))
+      current_dir = Keyword.get(opts, :cd, current_dir)
+
+      if args, do: send_arguments(port, args)
+      if envs != [], do: send_envs(port, envs)
+      if current_dir, do: send_current_dir(port, current_dir)
+    end)
+  end
+
   @messages [
     :command,
     :arg,
@@ -201,15 +800,175 @@ defmodule Rambo do
     :error,
     :stdout,
     :stderr,
-    :exit_status
+    :exit_status,
+    :stdin_chunk,
+    :stdin_close,
+    :signal,
+    :kill_grace,
+    :kill,
+    :killed,
+    :exit_signal,
+    :subreaper,
+    :timeout,
+    :timed_out,
+    :pty,
+    :resize,
+    :rlimit,
+    :limit_exceeded,
+    :usage,
+    :uid,
+    :gid,
+    :groups,
+    :umask,
+    :protocol_error,
+    :hello,
+    :spawn_error,
+    :pipe,
+    :pipefail,
+    :stage_statuses,
+    :stderr_mode,
+    :timestamps,
+    :line_framing,
+    :max_output,
+    :truncated,
+    :output_limit,
+    :request,
+    :credit,
+    :stdin_ack
   ]
 
   for {message, index} <- Enum.with_index(@messages) do
     Module.put_attribute(__MODULE__, message, <<index>>)
   end
 
+  # bumped only when existing messages change meaning
+  @protocol_version 1
+
+  # how long rambo may take to answer the hello before it is given up on
+  @hello_timeout 5_000
+
+  # bit positions of features the shim advertises in its hello
+  @capabilities [
+    :stream_stdin,
+    :kill_grace,
+    :subreaper,
+    :timeout,
+    :pty,
+    :limits,
+    :usage,
+    :credentials,
+    :pipeline,
+    :stderr_mode,
+    :timestamps,
+    :line_framing,
+    :max_output,
+    :credit,
+    :stdin_ack
+  ]
+
+  @all_capabilities (1 <<< length(@capabilities)) - 1
+
+  @doc false
+  def open_session do
+    rambo = Mix.Tasks.Compile.Rambo.find_rambo()
+    options = [:binary, :exit_status, {:packet, 4}, owner_env(), args: ["--session"]]
+    port = Port.open({:spawn_executable, rambo}, options)
+
+    # each request shakes hands again, but a session that cannot is refused
+    # before it takes any
+    with {:ok, _capabilities} <- handshake(port, rambo), do: {:ok, port}
+  end
+
+  # rambo watches the BEAM as well, in case it is killed without closing ports
+  defp owner_env do
+    {:env, [{~c"RAMBO_OWNER", String.to_charlist(System.pid())}]}
+  end
+
+  @doc false
+  def decode_request(<<@request, id::32, frame::binary>>), do: {:ok, id, frame}
+  def decode_request(_data), do: :error
+
+  @doc false
+  def kill_request(port, id) do
+    command({Rambo.Server, nil, port, id}, @kill)
+  rescue
+    # the session already exited
+    ArgumentError -> :ok
+  end
+
+  # a command has a port of its own or is a request of a Rambo.Server session
+  defp command({Rambo.Server, _server, port, id}, data) do
+    Port.command(port, [@request, <<id::32>>, data])
+  end
+
+  defp command(port, data) do
+    Port.command(port, data)
+  end
+
+  # requests cannot close the session, so they are killed in case they still run
+  defp close({Rambo.Server, _server, port, id} = request) do
+    kill_request(port, id)
+    Rambo.Server.checkin(request)
+  end
+
+  defp close(port) do
+    Port.close(port)
+  end
+
+  defp open?({Rambo.Server, _server, port, _id}), do: Port.info(port) != nil
+  defp open?(port), do: Port.info(port) != nil
+
+  defp handshake(port, rambo) do
+    command(port, [@hello, <<@protocol_version::32, @all_capabilities::64>>])
+    receive_hello(port, rambo)
+  end
+
+  defp receive_hello(port, rambo, rejected \\ false) do
+    receive do
+      {^port, {:data, @hello <> <<@protocol_version::32, bits::64>>}} ->
+        capabilities =
+          for {capability, bit} <- Enum.with_index(@capabilities),
+              (bits &&& 1 <<< bit) != 0,
+              do: capability
+
+        {:ok, capabilities}
+
+      {^port, {:data, @hello <> <<version::32, _bits::64>>}} ->
+        close(port)
+        {:error, "#{rambo} speaks protocol #{version} but #{@protocol_version} is required"}
+
+      # shims older than the handshake reject it and exit
+      {^port, {:data, _}} ->
+        receive_hello(port, rambo, true)
+
+      {^port, {:exit_status, _}} when rejected ->
+        {:error, "#{rambo} is outdated, remove it and recompile"}
+
+      {^port, {:exit_status, exit_status}} ->
+        {:error, "rambo exited with #{exit_status} during the handshake"}
+
+      {^port, :closed} ->
+        {:error, "rambo was closed during the handshake"}
+    after
+      @hello_timeout ->
+        close(port)
+        {:error, "#{rambo} did not answer the handshake"}
+    end
+  end
+
+  defp check_capabilities(port, capabilities, features) do
+    case Enum.find(features, fn {feature, used} -> used && feature not in capabilities end) do
+      nil ->
+        :ok
+
+      {feature, _used} ->
+        close(port)
+        {:error, "#{feature} is not supported by this platform or rambo binary"}
+    end
+  end
+
   defp send_command(port, command) do
-    Port.command(port, [@command, command])
+    command(port, [@command, command])
   end
 
   defp send_arguments(port, args) when is_list(args) do
@@ -219,67 +978,373 @@ defmodule Rambo do
   end
 
   defp send_arguments(port, arg) when is_binary(arg) do
-    Port.command(port, [@arg, arg])
+    command(port, [@arg, arg])
   end
 
   defp send_stdin(port, stdin) do
-    Port.command(port, [@stdin, stdin])
+    command(port, [@stdin, stdin])
+  end
+
+  # an empty chunk before EOT keeps stdin open for chunks after EOT
+  defp open_stdin(port) do
+    command(port, @stdin_chunk)
+  end
+
+  # chunks rambo may not have written to the command yet, so it never queues
+  # more however slowly the command reads
+  @stdin_window 16
+
+  defp stream_stdin(port, :open), do: stream_stdin(port, written_chunks())
+
+  defp stream_stdin(port, stream) do
+    spawn_link(fn ->
+      try do
+        Enum.reduce(stream, 0, &send_stdin_chunk(port, &1, &2))
+        send_stdin_close(port)
+      rescue
+        error in ArgumentError ->
+          # port already closed because the command exited
+          if open?(port), do: reraise(error, __STACKTRACE__)
+      end
+    end)
+  end
+
+  # rambo acks each chunk once written, which the process running the
+  # command forwards
+  defp send_stdin_chunk(port, chunk, @stdin_window) do
+    receive do
+      :stdin_ack -> send_stdin_chunk(port, chunk, @stdin_window - 1)
+    end
+  end
+
+  defp send_stdin_chunk(port, chunk, unacked) do
+    command(port, [@stdin_chunk, chunk])
+    unacked + 1
+  end
+
+  # chunks given to write/2 until close_stdin/1, as forwarded by the process
+  # running the command
+  defp written_chunks do
+    Stream.unfold(nil, fn nil ->
+      receive do
+        {:stdin, chunk} -> {chunk, nil}
+        :close_stdin -> nil
+      end
+    end)
+  end
+
+  defp send_stdin_close(port) do
+    command(port, @stdin_close)
+  end
+
+  defp stop_streaming(streamer) do
+    Process.unlink(streamer)
+    Process.exit(streamer, :kill)
   end
 
   defp send_envs(port, envs) do
     for {name, value} <- envs do
-      Port.command(port, [@env, <<byte_size(name)::32>>, name, value])
+      command(port, [@env, <<byte_size(name)::32>>, name, value])
     end
   end
 
   defp send_current_dir(port, current_dir) do
-    Port.command(port, [@current_dir, current_dir])
+    command(port, [@current_dir, current_dir])
+  end
+
+  defp send_signal(port, signal) do
+    name = signal |> Atom.to_string() |> String.upcase()
+    command(port, [@signal, name])
+  end
+
+  defp send_kill_grace(port, kill_grace) do
+    command(port, [@kill_grace, <<kill_grace::32>>])
+  end
+
+  defp send_subreaper(port) do
+    command(port, @subreaper)
+  end
+
+  defp send_timeout(port, timeout) do
+    command(port, [@timeout, <<timeout::32>>])
+  end
+
+  defp send_pty(port, true) do
+    send_pty(port, {24, 80})
+  end
+
+  defp send_pty(port, {rows, cols}) do
+    command(port, [@pty, <<rows::16, cols::16>>])
+  end
+
+  defp send_limits(port, limits) do
+    for {resource, value} <- limits do
+      command(port, [@rlimit, <<value::64>>, Atom.to_string(resource)])
+    end
+  end
+
+  defp send_uid(port, uid) do
+    command(port, [@uid, <<uid::32>>])
+  end
+
+  defp send_gid(port, gid) do
+    command(port, [@gid, <<gid::32>>])
+  end
+
+  defp send_groups(port, groups) do
+    command(port, [@groups | Enum.map(groups, &<<&1::32>>)])
+  end
+
+  defp send_umask(port, umask) do
+    command(port, [@umask, <<umask::32>>])
+  end
+
+  defp send_pipe(port) do
+    command(port, @pipe)
+  end
+
+  defp send_pipefail(port) do
+    command(port, @pipefail)
+  end
+
+  defp send_stderr_mode(port, :merge) do
+    command(port, [@stderr_mode, 1])
+  end
+
+  defp send_stderr_mode(port, :discard) do
+    command(port, [@stderr_mode, 2])
+  end
+
+  defp send_timestamps(port) do
+    command(port, @timestamps)
+  end
+
+  defp send_line_framing(port, :line) do
+    send_line_framing(port, {:line, 65_536})
+  end
+
+  defp send_line_framing(port, {:line, max_length}) when max_length > 0 do
+    command(port, [@line_framing, <<max_length::32>>])
+  end
+
+  defp send_max_output(port, stream, max) when is_integer(max) do
+    send_max_output(port, stream, {max, :truncate})
+  end
+
+  defp send_max_output(port, stream, {max, action}) when action in [:truncate, :kill] do
+    kill = if action == :kill, do: 1, else: 0
+    command(port, [@max_output, <<max::64, kill>>, stream])
+  end
+
+  defp send_credit(port, :frames, frames) do
+    command(port, [@credit, <<0, frames::32>>])
+  end
+
+  defp send_credit(port, :bytes, bytes) do
+    command(port, [@credit, <<1, bytes::32>>])
+  end
+
+  # output collected by run/3 is credited back once handled, while the owner
+  # of start/3 acks what it handled instead
+  defp return_credit(port, credit, output) when is_binary(output) do
+    return_credit(port, credit, byte_size(output))
+  end
+
+  defp return_credit(port, credit, size) do
+    for {unit, _amount} <- credit || [] do
+      send_credit(port, unit, if(unit == :frames, do: 1, else: size))
+    end
+  end
+
+  defp send_resize(port, rows, cols) do
+    command(port, [@resize, <<rows::16, cols::16>>])
+  end
+
+  defp send_kill(port) do
+    command(port, @kill)
   end
 
   defp run_command(port) do
-    Port.command(port, @eot)
+    command(port, @eot)
   end
 
-  defp receive_result(port, result, log) do
+  defp receive_result(port, result, log, kill_grace, credit, streamer) do
     receive do
       {^port, {:data, @error <> message}} ->
-        Port.close(port)
+        close(port)
         {:error, message}
 
+      {^port, {:data, @spawn_error <> spawn_error}} ->
+        close(port)
+        {:error, decode_spawn_error(spawn_error)}
+
+      {^port, {:data, @protocol_error <> reason}} ->
+        close(port)
+        {:error, {:protocol, reason}}
+
       {^port, {:data, @stdout <> stdout}} ->
-        maybe_log(:stdout, stdout, log)
-        result = Map.update(result, :out, [], &[&1 | stdout])
-        receive_result(port, result, log)
+        stdout = maybe_log(:stdout, stdout, log)
+        result = Map.update(result, :out, [], &collect(&1, stdout))
+        if result.out, do: return_credit(port, credit, stdout)
+        receive_result(port, result, log, kill_grace, credit, streamer)
 
       {^port, {:data, @stderr <> stderr}} ->
-        maybe_log(:stderr, stderr, log)
-        result = Map.update(result, :err, [], &[&1 | stderr])
-        receive_result(port, result, log)
+        stderr = maybe_log(:stderr, stderr, log)
+        result = Map.update(result, :err, [], &collect(&1, stderr))
+        if result.err, do: return_credit(port, credit, stderr)
+        receive_result(port, result, log, kill_grace, credit, streamer)
 
       {^port, {:data, @exit_status <> <<exit_status::32>>}} ->
         result = Map.put(result, :status, exit_status)
-        receive_result(port, result, log)
+        receive_result(port, result, log, kill_grace, credit, streamer)
+
+      {^port, {:data, @killed <> "SIGTERM"}} ->
+        result = Map.put(result, :killed_by, :sigterm)
+        receive_result(port, result, log, kill_grace, credit, streamer)
+
+      {^port, {:data, @killed <> "SIGKILL"}} ->
+        result = Map.put(result, :killed_by, :sigkill)
+        receive_result(port, result, log, kill_grace, credit, streamer)
+
+      {^port, {:data, @exit_signal <> <<signal::32, core_dumped>>}} ->
+        result = %{result | signal: signal, core_dumped: core_dumped == 1}
+        receive_result(port, result, log, kill_grace, credit, streamer)
+
+      {^port, {:data, @limit_exceeded <> "cpu"}} ->
+        result = Map.put(result, :limit_exceeded, :cpu)
+        receive_result(port, result, log, kill_grace, credit, streamer)
+
+      {^port, {:data, @limit_exceeded <> "fsize"}} ->
+        result = Map.put(result, :limit_exceeded, :fsize)
+        receive_result(port, result, log, kill_grace, credit, streamer)
+
+      {^port, {:data, @usage <> usage}} ->
+        result = Map.put(result, :usage, decode_usage(usage))
+        receive_result(port, result, log, kill_grace, credit, streamer)
+
+      {^port, {:data, @stage_statuses <> statuses}} ->
+        result = Map.put(result, :statuses, decode_statuses(statuses))
+        receive_result(port, result, log, kill_grace, credit, streamer)
 
       {^port, {:data, @eot}} ->
-        Port.close(port)
+        close(port)
 
-        if result.status == 0 do
-          {:ok, result}
-        else
-          {:error, result}
+        cond do
+          result.killed_by -> {:killed, result}
+          result.status == 0 -> {:ok, result}
+          true -> {:error, result}
         end
 
+      {^port, {:data, @timed_out}} ->
+        close(port)
+        {:timeout, result}
+
+      {^port, {:data, @output_limit}} ->
+        close(port)
+        {:output_limit, result}
+
+      {^port, {:data, @stdin_ack}} ->
+        send(streamer, :stdin_ack)
+        receive_result(port, result, log, kill_grace, credit, streamer)
+
+      {^port, {:data, @truncated <> _stream}} ->
+        result = Map.put(result, :truncated, true)
+        receive_result(port, result, log, kill_grace, credit, streamer)
+
       {^port, {:exit_status, exit_status}} ->
         {:error, "rambo exited with #{exit_status}"}
 
+      {Rambo, {:signal, signal}} ->
+        send_signal(port, signal)
+        receive_result(port, result, log, kill_grace, credit, streamer)
+
+      {Rambo, {:resize, rows, cols}} ->
+        send_resize(port, rows, cols)
+        receive_result(port, result, log, kill_grace, credit, streamer)
+
+      {Rambo, {:stdin, chunk}} ->
+        if streamer, do: send(streamer, {:stdin, chunk})
+        receive_result(port, result, log, kill_grace, credit, streamer)
+
+      {Rambo, :close_stdin} ->
+        if streamer, do: send(streamer, :close_stdin)
+        receive_result(port, result, log, kill_grace, credit, streamer)
+
+      {Rambo, {:ack, size}} ->
+        return_credit(port, credit, size)
+        receive_result(port, result, log, kill_grace, credit, streamer)
+
+      :kill when is_integer(kill_grace) ->
+        send_kill(port)
+        receive_result(port, result, log, kill_grace, credit, streamer)
+
       :kill ->
-        Port.close(port)
-        {:killed, result}
+        close(port)
+        {:killed, %{result | killed_by: :sigkill}}
     end
   end
 
+  defp decode_usage(
+         <<user_time::64, system_time::64, max_rss::64, minor_faults::64, major_faults::64,
+           voluntary_switches::64, involuntary_switches::64, wall_time::64>>
+       ) do
+    %{
+      user_time: user_time,
+      system_time: system_time,
+      max_rss: max_rss,
+      minor_faults: minor_faults,
+      major_faults: major_faults,
+      voluntary_switches: voluntary_switches,
+      involuntary_switches: involuntary_switches,
+      wall_time: wall_time
+    }
+  end
+
+  defp decode_statuses(statuses) do
+    for <<kind, value::signed-32 <- statuses>> do
+      case kind do
+        0 -> value
+        1 -> {:signal, value}
+        2 -> nil
+      end
+    end
+  end
+
+  defp decode_spawn_error(
+         <<errno::signed-32, kind_length::32, kind::binary-size(kind_length), name_length::32,
+           name::binary-size(name_length), program::binary>>
+       ) do
+    kind = kind |> Macro.underscore() |> String.to_atom()
+    reason = if name == "", do: kind, else: name |> String.downcase() |> String.to_atom()
+    %Rambo.SpawnError{reason: reason, kind: kind, errno: errno, program: program}
+  end
+
+  # usage is always reported but only kept when asked for
+  defp maybe_usage({reason, %Rambo{} = result}, false) do
+    {reason, %{result | usage: nil}}
+  end
+
+  defp maybe_usage(result, _usage) do
+    result
+  end
+
+  defp log_to(log) when is_function(log), do: log
+  defp log_to(true), do: [:stdout, :stderr]
+  defp log_to(log), do: [log]
+
+  defp maybe_log(to, <<seq::64, at::64, output::binary>>, {:timestamps, log})
+       when is_function(log) do
+    log.({to, output, %{seq: seq, at: at}})
+    output
+  end
+
+  defp maybe_log(to, <<_seq::64, _at::64, output::binary>>, {:timestamps, log}) do
+    maybe_log(to, output, log)
+  end
+
   defp maybe_log(to, output, log) when is_function(log) do
     log.({to, output})
+    output
   end
 
   defp maybe_log(to, output, log) do
@@ -292,8 +1357,14 @@ defmodule Rambo do
 
       IO.binwrite(device, output)
     end
+
+    output
   end
 
+  # output is streamed rather than collected for start/3
+  defp collect(nil, _output), do: nil
+  defp collect(collected, output), do: [collected | output]
+
   defp output_to_binary({reason, %Rambo{out: out, err: err} = result}) do
     {reason, %{result | out: to_binary(out), err: to_binary(err)}}
   end
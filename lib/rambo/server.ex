@@ -0,0 +1,127 @@
+defmodule Rambo.Server do
+  @moduledoc """
+  Runs commands over long-lived `rambo` processes instead of starting one for
+  every command.
+
+  Start it in your supervision tree, then pass it to `Rambo.run/3` or
+  `Rambo.pipeline/2` with the `:server` option.
+
+      children = [
+        {Rambo.Server, name: MyApp.Rambo}
+      ]
+
+      Rambo.run("ls", server: MyApp.Rambo)
+
+  Each `rambo` runs many commands at once, and commands are spread across
+  them in turn. When your process exits, its command is killed. When a
+  `rambo` exits, its commands return an error and it is started again.
+
+  ## Options
+
+    * `:name` - registers the server under this name
+    * `:size` - how many `rambo` processes to start. Defaults to the number of
+    schedulers online.
+
+  Resource usage other than `:wall_time` is not measured and `:subreaper` is
+  not supported, as commands share their `rambo`.
+  """
+
+  use GenServer
+
+  @doc """
+  Starts a server linked to the current process.
+
+  Fails with `{:error, reason}` if `rambo` cannot run sessions, such as when
+  it is outdated.
+  """
+  @spec start_link(Keyword.t()) :: GenServer.on_start()
+  def start_link(opts \\ []) do
+    {name, opts} = Keyword.pop(opts, :name)
+    GenServer.start_link(__MODULE__, opts, if(name, do: [name: name], else: []))
+  end
+
+  @doc false
+  def checkout(server) do
+    GenServer.call(server, :checkout)
+  end
+
+  @doc false
+  def checkin({__MODULE__, server, port, id}) do
+    GenServer.cast(server, {:checkin, port, id})
+  end
+
+  @impl true
+  def init(opts) do
+    size = Keyword.get(opts, :size, System.schedulers_online())
+
+    case open_sessions(size, []) do
+      {:ok, ports} -> {:ok, %{ports: ports, requests: %{}, next_id: 1}}
+      {:error, reason} -> {:stop, reason}
+    end
+  end
+
+  # sessions already opened close with the server if another one fails
+  defp open_sessions(0, ports), do: {:ok, ports}
+
+  defp open_sessions(size, ports) do
+    with {:ok, port} <- Rambo.open_session(), do: open_sessions(size - 1, [port | ports])
+  end
+
+  @impl true
+  def handle_call(:checkout, {pid, _tag}, state) do
+    %{ports: [port | ports], requests: requests, next_id: id} = state
+    monitor = Process.monitor(pid)
+    requests = Map.put(requests, {port, id}, {pid, monitor})
+    state = %{state | ports: ports ++ [port], requests: requests, next_id: id + 1}
+    {:reply, {__MODULE__, self(), port, id}, state}
+  end
+
+  @impl true
+  def handle_cast({:checkin, port, id}, state) do
+    {request, requests} = Map.pop(state.requests, {port, id})
+    if request, do: Process.demonitor(elem(request, 1), [:flush])
+    {:noreply, %{state | requests: requests}}
+  end
+
+  @impl true
+  def handle_info({port, {:data, data}}, state) when is_port(port) do
+    # frames of finished requests and from outdated rambos are dropped
+    with {:ok, id, frame} <- Rambo.decode_request(data),
+         {pid, _monitor} <- Map.get(state.requests, {port, id}) do
+      send(pid, {{__MODULE__, self(), port, id}, {:data, frame}})
+    end
+
+    {:noreply, state}
+  end
+
+  def handle_info({port, {:exit_status, status}}, state) when is_port(port) do
+    {lost, requests} = Enum.split_with(state.requests, fn {{from, _id}, _} -> from == port end)
+
+    for {{^port, id}, {pid, monitor}} <- lost do
+      Process.demonitor(monitor, [:flush])
+      send(pid, {{__MODULE__, self(), port, id}, {:exit_status, status}})
+    end
+
+    state = %{state | requests: Map.new(requests)}
+
+    case Rambo.open_session() do
+      {:ok, session} ->
+        ports = for from <- state.ports, do: if(from == port, do: session, else: from)
+        {:noreply, %{state | ports: ports}}
+
+      {:error, reason} ->
+        {:stop, reason, state}
+    end
+  end
+
+  def handle_info({:DOWN, monitor, :process, _pid, _reason}, state) do
+    case Enum.find(state.requests, fn {_request, {_pid, from}} -> from == monitor end) do
+      {{port, id} = request, _} ->
+        Rambo.kill_request(port, id)
+        {:noreply, %{state | requests: Map.delete(state.requests, request)}}
+
+      nil ->
+        {:noreply, state}
+    end
+  end
+end
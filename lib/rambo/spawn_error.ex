@@ -0,0 +1,24 @@
+defmodule Rambo.SpawnError do
+  @moduledoc """
+  Returned as `{:error, %Rambo.SpawnError{}}` when your command could not be
+  started.
+
+  `:reason` is the POSIX error, such as `:enoent` when the program does not
+  exist or `:eacces` when it is not executable, or the `:kind` of error when
+  there is none. `:errno` is the raw OS error code, or `0` if there is none.
+  """
+
+  defexception [:reason, :kind, :errno, :program]
+
+  @type t :: %__MODULE__{
+          reason: atom(),
+          kind: atom(),
+          errno: integer(),
+          program: String.t()
+        }
+
+  @impl true
+  def message(%__MODULE__{reason: reason, program: program}) do
+    "failed to spawn #{program}: #{:file.format_error(reason)}"
+  end
+end
@@ -1,22 +1,72 @@
-use futures::future::FutureExt;
+#![recursion_limit = "256"]
+
+mod owner;
+mod pty;
+
+use futures::channel::mpsc;
+use futures::future::{Either, Fuse, FutureExt};
+use futures::lock::Mutex;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use pty::Pty;
+use std::cell::Cell;
 use std::collections::HashMap;
+use std::ffi::OsString;
 use std::io::ErrorKind;
+use std::panic::AssertUnwindSafe;
 use std::process::{ExitStatus, Stdio};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 use tokio::prelude::*;
-use tokio::process::{ChildStdin, Command};
+use tokio::process::Command;
+use tokio::time::Delay;
 
 #[derive(Debug)]
 enum Message {
-    Command(String),
-    Arg(String),
+    Command(OsString),
+    Arg(OsString),
     Stdin(Vec<u8>),
-    Env(String, String),
-    CurrentDir(String),
+    Env(OsString, OsString),
+    CurrentDir(OsString),
     Eot,
     Error(String),
     Stdout(Vec<u8>),
     Stderr(Vec<u8>),
     ExitStatus(i32),
+    StdinChunk(Vec<u8>),
+    StdinClose,
+    Signal(String),
+    KillGrace(u32),
+    Kill,
+    Killed(String),
+    ExitSignal(i32, bool),
+    Subreaper,
+    Timeout(u32),
+    TimedOut,
+    Pty(u16, u16),
+    Resize(u16, u16),
+    Rlimit(String, u64),
+    LimitExceeded(String),
+    Usage(Usage),
+    Uid(u32),
+    Gid(u32),
+    Groups(Vec<u32>),
+    Umask(u32),
+    ProtocolError(String),
+    Hello(u32, u64),
+    SpawnError(i32, String, String, OsString),
+    Pipe,
+    Pipefail,
+    StageStatuses(Vec<Option<ExitStatus>>),
+    StderrMode(StderrMode),
+    Timestamps,
+    LineFraming(u32),
+    MaxOutput(String, u64, bool),
+    Truncated(String),
+    OutputLimit,
+    Request(u32, Vec<u8>),
+    Credit(CreditUnit, u32),
+    StdinAck,
 }
 
 const COMMAND: u8 = 0;
@@ -29,39 +79,438 @@ const ERROR: u8 = 6;
 const STDOUT: u8 = 7;
 const STDERR: u8 = 8;
 const EXIT_STATUS: u8 = 9;
+const STDIN_CHUNK: u8 = 10;
+const STDIN_CLOSE: u8 = 11;
+const SIGNAL: u8 = 12;
+const KILL_GRACE: u8 = 13;
+const KILL: u8 = 14;
+const KILLED: u8 = 15;
+const EXIT_SIGNAL: u8 = 16;
+const SUBREAPER: u8 = 17;
+const TIMEOUT: u8 = 18;
+const TIMED_OUT: u8 = 19;
+const PTY: u8 = 20;
+const RESIZE: u8 = 21;
+const RLIMIT: u8 = 22;
+const LIMIT_EXCEEDED: u8 = 23;
+const USAGE: u8 = 24;
+const UID: u8 = 25;
+const GID: u8 = 26;
+const GROUPS: u8 = 27;
+const UMASK: u8 = 28;
+const PROTOCOL_ERROR: u8 = 29;
+const HELLO: u8 = 30;
+const SPAWN_ERROR: u8 = 31;
+const PIPE: u8 = 32;
+const PIPEFAIL: u8 = 33;
+const STAGE_STATUSES: u8 = 34;
+const STDERR_MODE: u8 = 35;
+const TIMESTAMPS: u8 = 36;
+const LINE_FRAMING: u8 = 37;
+const MAX_OUTPUT: u8 = 38;
+const TRUNCATED: u8 = 39;
+const OUTPUT_LIMIT: u8 = 40;
+const REQUEST: u8 = 41;
+const CREDIT: u8 = 42;
+const STDIN_ACK: u8 = 43;
+
+// Bumped only when existing messages change meaning. New messages are
+// advertised as capabilities instead.
+const PROTOCOL_VERSION: u32 = 1;
+
+// most bytes read from a child stream at once
+const READ_SIZE: usize = 65_536;
+
+mod capability {
+    pub const STREAM_STDIN: u64 = 1 << 0;
+    pub const KILL_GRACE: u64 = 1 << 1;
+    pub const SUBREAPER: u64 = 1 << 2;
+    pub const TIMEOUT: u64 = 1 << 3;
+    pub const PTY: u64 = 1 << 4;
+    pub const LIMITS: u64 = 1 << 5;
+    pub const USAGE: u64 = 1 << 6;
+    pub const CREDENTIALS: u64 = 1 << 7;
+    pub const PIPELINE: u64 = 1 << 8;
+    pub const STDERR_MODE: u64 = 1 << 9;
+    pub const TIMESTAMPS: u64 = 1 << 10;
+    pub const LINE_FRAMING: u64 = 1 << 11;
+    pub const MAX_OUTPUT: u64 = 1 << 12;
+    pub const CREDIT: u64 = 1 << 13;
+    pub const STDIN_ACK: u64 = 1 << 14;
+}
+
+fn capabilities() -> u64 {
+    let mut capabilities =
+        capability::STREAM_STDIN | capability::KILL_GRACE | capability::TIMEOUT | capability::USAGE;
+    capabilities |= capability::PIPELINE | capability::STDERR_MODE | capability::TIMESTAMPS;
+    capabilities |= capability::LINE_FRAMING | capability::MAX_OUTPUT | capability::CREDIT;
+    capabilities |= capability::STDIN_ACK;
+    if cfg!(unix) {
+        capabilities |= capability::PTY | capability::LIMITS | capability::CREDENTIALS;
+    }
+    if cfg!(target_os = "linux") {
+        capabilities |= capability::SUBREAPER;
+    }
+    capabilities
+}
+
+// Frames from erlang that cannot be decoded or arrive out of order. They
+// travel as io::Error so they can be told apart from failures of the command.
+#[derive(Debug)]
+enum ProtocolError {
+    Empty,
+    UnknownMessage(u8),
+    Malformed(u8),
+    InvalidUtf8(u8),
+    Unexpected(String),
+    MissingCommand,
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ProtocolError::Empty => write!(formatter, "empty message"),
+            ProtocolError::UnknownMessage(tag) => write!(formatter, "unknown message {}", tag),
+            ProtocolError::Malformed(tag) => write!(formatter, "malformed message {}", tag),
+            ProtocolError::InvalidUtf8(tag) => write!(formatter, "message {} is not UTF-8", tag),
+            ProtocolError::Unexpected(name) => write!(formatter, "unexpected message {}", name),
+            ProtocolError::MissingCommand => write!(formatter, "command required"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<ProtocolError> for io::Error {
+    fn from(error: ProtocolError) -> io::Error {
+        io::Error::new(ErrorKind::InvalidData, error)
+    }
+}
+
+impl ProtocolError {
+    fn from_io(error: &io::Error) -> Option<&ProtocolError> {
+        error.get_ref().and_then(|error| error.downcast_ref())
+    }
+}
+
+// Keeps the attempted program alongside the reason it could not be spawned.
+#[derive(Debug)]
+struct SpawnError {
+    program: OsString,
+    error: io::Error,
+}
+
+impl std::fmt::Display for SpawnError {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            formatter,
+            "failed to spawn {:?}: {}",
+            self.program, self.error
+        )
+    }
+}
+
+impl std::error::Error for SpawnError {}
+
+impl SpawnError {
+    fn into_io(program: &OsString, error: io::Error) -> io::Error {
+        let kind = error.kind();
+        let program = program.clone();
+        io::Error::new(kind, SpawnError { program, error })
+    }
+
+    fn from_io(error: &io::Error) -> Option<&SpawnError> {
+        error.get_ref().and_then(|error| error.downcast_ref())
+    }
+
+    fn to_message(&self) -> Message {
+        Message::SpawnError(
+            self.error.raw_os_error().unwrap_or(0),
+            format!("{:?}", self.error.kind()),
+            errno_name(&self.error).to_string(),
+            self.program.clone(),
+        )
+    }
+}
 
 impl Message {
-    fn from_bytes(bytes: Vec<u8>) -> Message {
-        match bytes[0] {
-            COMMAND => Message::Command(Message::string_from_bytes(&bytes[1..])),
-            ARG => Message::Arg(Message::string_from_bytes(&bytes[1..])),
-            STDIN => Message::Stdin(bytes[1..].to_vec()),
+    fn from_bytes(bytes: Vec<u8>) -> Result<Message, ProtocolError> {
+        let (&tag, payload) = bytes.split_first().ok_or(ProtocolError::Empty)?;
+        let expect_length = |length: usize| {
+            if payload.len() == length {
+                Ok(())
+            } else {
+                Err(ProtocolError::Malformed(tag))
+            }
+        };
+
+        let message = match tag {
+            COMMAND => Message::Command(Message::os_string_from_bytes(payload)),
+            ARG => Message::Arg(Message::os_string_from_bytes(payload)),
+            STDIN => Message::Stdin(payload.to_vec()),
             ENV => {
-                let mut name_length: [u8; 4] = [0, 0, 0, 0];
-                name_length.copy_from_slice(&bytes[1..5]);
-                let name_length = u32::from_be_bytes(name_length) as usize;
-                let name_end = 5 + name_length;
-                let name = Message::string_from_bytes(&bytes[5..name_end]);
-                let value = Message::string_from_bytes(&bytes[name_end..]);
+                if payload.len() < 4 {
+                    return Err(ProtocolError::Malformed(tag));
+                }
+                let name_length = Message::u32_from_bytes(&payload[..4]) as usize;
+                let name_end = 4usize
+                    .checked_add(name_length)
+                    .filter(|&name_end| name_end <= payload.len())
+                    .ok_or(ProtocolError::Malformed(tag))?;
+                let name = Message::os_string_from_bytes(&payload[4..name_end]);
+                let value = Message::os_string_from_bytes(&payload[name_end..]);
                 Message::Env(name, value)
             }
-            CURRENT_DIR => Message::CurrentDir(Message::string_from_bytes(&bytes[1..])),
-            EOT => Message::Eot,
-            _ => panic!("unexpected message {:?}", bytes),
-        }
+            CURRENT_DIR => Message::CurrentDir(Message::os_string_from_bytes(payload)),
+            EOT => {
+                expect_length(0)?;
+                Message::Eot
+            }
+            STDIN_CHUNK => Message::StdinChunk(payload.to_vec()),
+            STDIN_CLOSE => {
+                expect_length(0)?;
+                Message::StdinClose
+            }
+            SIGNAL => {
+                let name = Message::string_from_bytes(tag, payload)?;
+                // an unknown signal would be ignored as if it had been sent
+                #[cfg(unix)]
+                {
+                    if signal_number(&name).is_none() {
+                        return Err(ProtocolError::Malformed(tag));
+                    }
+                }
+                Message::Signal(name)
+            }
+            KILL_GRACE => {
+                expect_length(4)?;
+                Message::KillGrace(Message::u32_from_bytes(payload))
+            }
+            KILL => {
+                expect_length(0)?;
+                Message::Kill
+            }
+            SUBREAPER => {
+                expect_length(0)?;
+                Message::Subreaper
+            }
+            TIMEOUT => {
+                expect_length(4)?;
+                Message::Timeout(Message::u32_from_bytes(payload))
+            }
+            PTY => {
+                expect_length(4)?;
+                Message::Pty(
+                    Message::u16_from_bytes(&payload[..2]),
+                    Message::u16_from_bytes(&payload[2..]),
+                )
+            }
+            RESIZE => {
+                expect_length(4)?;
+                Message::Resize(
+                    Message::u16_from_bytes(&payload[..2]),
+                    Message::u16_from_bytes(&payload[2..]),
+                )
+            }
+            RLIMIT => {
+                if payload.len() < 8 {
+                    return Err(ProtocolError::Malformed(tag));
+                }
+                Message::Rlimit(
+                    Message::string_from_bytes(tag, &payload[8..])?,
+                    Message::u64_from_bytes(&payload[..8]),
+                )
+            }
+            UID => {
+                expect_length(4)?;
+                Message::Uid(Message::u32_from_bytes(payload))
+            }
+            GID => {
+                expect_length(4)?;
+                Message::Gid(Message::u32_from_bytes(payload))
+            }
+            GROUPS => {
+                if payload.len() % 4 != 0 {
+                    return Err(ProtocolError::Malformed(tag));
+                }
+                Message::Groups(payload.chunks(4).map(Message::u32_from_bytes).collect())
+            }
+            UMASK => {
+                expect_length(4)?;
+                Message::Umask(Message::u32_from_bytes(payload))
+            }
+            PIPE => {
+                expect_length(0)?;
+                Message::Pipe
+            }
+            PIPEFAIL => {
+                expect_length(0)?;
+                Message::Pipefail
+            }
+            STDERR_MODE => {
+                expect_length(1)?;
+                Message::StderrMode(match payload[0] {
+                    0 => StderrMode::Separate,
+                    1 => StderrMode::Merge,
+                    2 => StderrMode::Discard,
+                    _ => return Err(ProtocolError::Malformed(tag)),
+                })
+            }
+            TIMESTAMPS => {
+                expect_length(0)?;
+                Message::Timestamps
+            }
+            LINE_FRAMING => {
+                expect_length(4)?;
+                match Message::u32_from_bytes(payload) {
+                    0 => return Err(ProtocolError::Malformed(tag)),
+                    max_line => Message::LineFraming(max_line),
+                }
+            }
+            MAX_OUTPUT => {
+                if payload.len() < 9 || payload[8] > 1 {
+                    return Err(ProtocolError::Malformed(tag));
+                }
+                Message::MaxOutput(
+                    Message::string_from_bytes(tag, &payload[9..])?,
+                    Message::u64_from_bytes(&payload[..8]),
+                    payload[8] == 1,
+                )
+            }
+            CREDIT => {
+                expect_length(5)?;
+                let unit = match payload[0] {
+                    0 => CreditUnit::Frames,
+                    1 => CreditUnit::Bytes,
+                    _ => return Err(ProtocolError::Malformed(tag)),
+                };
+                Message::Credit(unit, Message::u32_from_bytes(&payload[1..]))
+            }
+            REQUEST => {
+                if payload.len() < 4 {
+                    return Err(ProtocolError::Malformed(tag));
+                }
+                Message::Request(
+                    Message::u32_from_bytes(&payload[..4]),
+                    payload[4..].to_vec(),
+                )
+            }
+            HELLO => {
+                expect_length(12)?;
+                Message::Hello(
+                    Message::u32_from_bytes(&payload[..4]),
+                    Message::u64_from_bytes(&payload[4..]),
+                )
+            }
+            _ => return Err(ProtocolError::UnknownMessage(tag)),
+        };
+        Ok(message)
+    }
+
+    fn string_from_bytes(tag: u8, bytes: &[u8]) -> Result<String, ProtocolError> {
+        String::from_utf8(bytes.to_vec()).map_err(|_| ProtocolError::InvalidUtf8(tag))
+    }
+
+    // Paths and arguments are passed through untouched, they need not be UTF-8.
+    #[cfg(unix)]
+    fn os_string_from_bytes(bytes: &[u8]) -> OsString {
+        use std::os::unix::ffi::OsStrExt;
+        std::ffi::OsStr::from_bytes(bytes).to_os_string()
+    }
+
+    // Windows strings are UTF-16, so invalid UTF-8 cannot be represented.
+    #[cfg(not(unix))]
+    fn os_string_from_bytes(bytes: &[u8]) -> OsString {
+        String::from_utf8_lossy(bytes).into_owned().into()
     }
 
-    fn string_from_bytes(bytes: &[u8]) -> String {
-        String::from_utf8(bytes.to_vec()).unwrap()
+    #[cfg(unix)]
+    fn os_string_to_bytes(string: &OsString) -> Vec<u8> {
+        use std::os::unix::ffi::OsStrExt;
+        string.as_bytes().to_vec()
+    }
+
+    #[cfg(not(unix))]
+    fn os_string_to_bytes(string: &OsString) -> Vec<u8> {
+        string.to_string_lossy().into_owned().into_bytes()
+    }
+
+    fn u16_from_bytes(bytes: &[u8]) -> u16 {
+        let mut number: [u8; 2] = [0, 0];
+        number.copy_from_slice(bytes);
+        u16::from_be_bytes(number)
+    }
+
+    fn u32_from_bytes(bytes: &[u8]) -> u32 {
+        let mut number: [u8; 4] = [0, 0, 0, 0];
+        number.copy_from_slice(bytes);
+        u32::from_be_bytes(number)
+    }
+
+    fn u64_from_bytes(bytes: &[u8]) -> u64 {
+        let mut number: [u8; 8] = [0; 8];
+        number.copy_from_slice(bytes);
+        u64::from_be_bytes(number)
     }
 
     fn to_bytes(&self) -> Vec<u8> {
         match self {
             Message::Eot => vec![0, 0, 0, 1, EOT],
+            Message::TimedOut => vec![0, 0, 0, 1, TIMED_OUT],
+            Message::OutputLimit => vec![0, 0, 0, 1, OUTPUT_LIMIT],
+            Message::StdinAck => vec![0, 0, 0, 1, STDIN_ACK],
+            Message::Truncated(stream) => Message::to_vec(TRUNCATED, stream.as_bytes()),
             Message::Error(message) => Message::to_vec(ERROR, message.as_bytes()),
+            Message::ProtocolError(reason) => Message::to_vec(PROTOCOL_ERROR, reason.as_bytes()),
             Message::Stdout(buffer) => Message::to_vec(STDOUT, buffer.as_slice()),
             Message::Stderr(buffer) => Message::to_vec(STDERR, buffer.as_slice()),
             Message::ExitStatus(code) => Message::to_vec(EXIT_STATUS, &code.to_be_bytes()),
+            Message::Killed(signal) => Message::to_vec(KILLED, signal.as_bytes()),
+            Message::ExitSignal(signal, core_dumped) => {
+                let mut bytes = signal.to_be_bytes().to_vec();
+                bytes.push(*core_dumped as u8);
+                Message::to_vec(EXIT_SIGNAL, &bytes)
+            }
+            Message::LimitExceeded(resource) => {
+                Message::to_vec(LIMIT_EXCEEDED, resource.as_bytes())
+            }
+            Message::Hello(version, capabilities) => {
+                let mut bytes = version.to_be_bytes().to_vec();
+                bytes.extend(&capabilities.to_be_bytes());
+                Message::to_vec(HELLO, &bytes)
+            }
+            Message::SpawnError(errno, kind, name, program) => {
+                let mut bytes = errno.to_be_bytes().to_vec();
+                for field in &[kind, name] {
+                    bytes.extend(&(field.len() as u32).to_be_bytes());
+                    bytes.extend(field.as_bytes());
+                }
+                bytes.extend(Message::os_string_to_bytes(program));
+                Message::to_vec(SPAWN_ERROR, &bytes)
+            }
+            // each stage is an exit code, a signal or unknown if it was killed
+            Message::StageStatuses(statuses) => {
+                let mut bytes = vec![];
+                for status in statuses {
+                    let exit = status.map(|status| (status.code(), exit_signal(status)));
+                    let (kind, value) = match exit {
+                        Some((Some(code), _)) => (0u8, code),
+                        Some((None, Some((signal, _)))) => (1, signal),
+                        _ => (2, 0),
+                    };
+                    bytes.push(kind);
+                    bytes.extend(&value.to_be_bytes());
+                }
+                Message::to_vec(STAGE_STATUSES, &bytes)
+            }
+            Message::Usage(usage) => {
+                let bytes: Vec<u8> = usage
+                    .to_numbers()
+                    .iter()
+                    .flat_map(|number| number.to_be_bytes().to_vec())
+                    .collect();
+                Message::to_vec(USAGE, &bytes)
+            }
             _ => panic!("{} cannot be encoded to bytes", self),
         }
     }
@@ -74,14 +523,32 @@ impl Message {
         buffer
     }
 
-    async fn read_from_erlang() -> io::Result<Message> {
-        let mut stdin = tokio::io::stdin();
-        let length = stdin.read_u32().await? as usize;
+    // Erlang is gone once it closes standard input, or once its owner dies
+    // without closing it.
+    async fn read_frame() -> io::Result<Vec<u8>> {
+        let read = async {
+            let mut stdin = tokio::io::stdin();
+            let length = stdin.read_u32().await? as usize;
+
+            let mut buffer: Vec<u8> = vec![0; length];
+            stdin.read_exact(&mut buffer).await?;
+            Ok(buffer)
+        };
+        match futures::future::select(Box::pin(read), owner::gone()).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Err(ErrorKind::UnexpectedEof.into()),
+        }
+    }
 
-        let mut buffer: Vec<u8> = vec![0; length];
-        stdin.read_exact(&mut buffer).await?;
+    // Requests of a session read the frames the session hands them instead.
+    async fn read_from_erlang() -> io::Result<Message> {
+        let buffer = match CURRENT_REQUEST.try_with(Rc::clone) {
+            Ok(request) => request.frames.lock().await.next().await,
+            Err(_) => Some(Message::read_frame().await?),
+        };
+        let buffer = buffer.ok_or_else(|| io::Error::from(ErrorKind::UnexpectedEof))?;
 
-        let message = Message::from_bytes(buffer);
+        let message = Message::from_bytes(buffer)?;
 
         if unsafe { DEBUG } {
             eprint!("→ {:?}\r\n", message);
@@ -90,55 +557,210 @@ impl Message {
         Ok(message)
     }
 
-    async fn monitor_erlang() -> std::io::Error {
+    // Returns on messages that need the child, handing back the stdin sender
+    // so monitoring can resume afterwards.
+    async fn monitor_erlang(
+        mut chunks: Option<mpsc::UnboundedSender<Vec<u8>>>,
+        credit: Option<&Credit>,
+    ) -> (io::Result<Message>, Option<mpsc::UnboundedSender<Vec<u8>>>) {
         loop {
             match Message::read_from_erlang().await {
-                Err(error) if error.kind() == ErrorKind::UnexpectedEof => return error,
+                Ok(Message::StdinChunk(bytes)) => {
+                    if let Some(sender) = chunks.as_mut() {
+                        if sender.unbounded_send(bytes).is_err() {
+                            // child stopped reading, discard remaining chunks
+                            chunks = None;
+                        }
+                    }
+                }
+                Ok(Message::StdinClose) => chunks = None,
+                Ok(Message::Credit(unit, amount)) => {
+                    if let Some(credit) = credit {
+                        credit.grant(unit, amount);
+                    }
+                }
+                Ok(message @ Message::Signal(_))
+                | Ok(message @ Message::Kill)
+                | Ok(message @ Message::Resize(_, _)) => return (Ok(message), chunks),
+                Err(error)
+                    if error.kind() == ErrorKind::UnexpectedEof
+                        || ProtocolError::from_io(&error).is_some() =>
+                {
+                    return (Err(error), chunks)
+                }
                 _ => (),
             }
         }
     }
 
     async fn write_to_erlang(&self) {
-        let mut stdout = tokio::io::stdout();
-        stdout
-            .write_all(&self.to_bytes())
+        self.try_write_to_erlang()
             .await
             .expect("failed to write to erlang");
-        stdout.flush().await.expect("failed to flush to erlang");
+    }
+
+    async fn try_write_to_erlang(&self) -> io::Result<()> {
+        let mut bytes = self.to_bytes();
+        if let Ok(id) = CURRENT_REQUEST.try_with(|request| request.id) {
+            let mut payload = id.to_be_bytes().to_vec();
+            payload.extend(&bytes[4..]);
+            bytes = Message::to_vec(REQUEST, &payload);
+        }
+
+        // frames of concurrent streams and requests must not interleave
+        let lock = WRITE_LOCK.with(Rc::clone);
+        let _guard = lock.lock().await;
+        let mut stdout = tokio::io::stdout();
+        stdout.write_all(&bytes).await?;
+        stdout.flush().await?;
 
         if unsafe { DEBUG } {
             eprint!("← {:?}\r\n", self);
         }
+        Ok(())
     }
 
-    async fn stream_to_child(mut stdin: ChildStdin, input: Option<Vec<u8>>) -> io::Result<()> {
+    async fn stream_to_child<W>(
+        mut stdin: W,
+        input: Option<Vec<u8>>,
+        chunks: Option<mpsc::UnboundedReceiver<Vec<u8>>>,
+        terminal: bool,
+    ) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut line_start = true;
         if let Some(input) = input {
-            stdin.write_all(&input.as_slice()).await?;
+            stdin.write_all(input.as_slice()).await?;
+            stdin.flush().await?;
+            line_start = input.last().map_or(line_start, |&byte| byte == b'\n');
+        }
+        if let Some(mut chunks) = chunks {
+            while let Some(chunk) = chunks.next().await {
+                stdin.write_all(chunk.as_slice()).await?;
+                stdin.flush().await?;
+                // erlang sends more once the chunks it has sent are written,
+                // and once it is gone there is nobody left to stream for
+                Message::StdinAck.try_write_to_erlang().await?;
+                line_start = chunk.last().map_or(line_start, |&byte| byte == b'\n');
+            }
+        }
+        // terminals cannot be closed, EOF is ^D at the start of a line instead
+        if terminal {
+            let eof: &[u8] = if line_start { b"\x04" } else { b"\x04\x04" };
+            stdin.write_all(eof).await?;
             stdin.flush().await?;
         }
         Ok(())
     }
 
-    async fn stream_to_erlang<S, F>(mut stream: S, create_message: F) -> io::Result<()>
+    // Framed by lines, only complete lines are sent, one per message, unless
+    // they outgrow max_line and are split. Output beyond its limit is read
+    // but never sent. Reads are capped, so a chunk waiting for credit holds
+    // at most READ_SIZE bytes besides an unfinished line.
+    async fn stream_to_erlang<S, F>(
+        mut stream: S,
+        create_message: F,
+        clock: Option<&Clock>,
+        max_line: Option<usize>,
+        limit: Option<&OutputLimit>,
+        credit: Option<&Credit>,
+    ) -> io::Result<()>
     where
-        S: AsyncRead,
+        S: AsyncRead + Unpin,
         F: Fn(Vec<u8>) -> Message,
     {
         let mut buffer = vec![];
-        while stream.read_buf(&mut buffer).await? > 0 {
-            let message = create_message(buffer.clone());
-            message.write_to_erlang().await;
-            buffer.clear();
+        let mut read = vec![0; READ_SIZE];
+        loop {
+            let count = stream.read(&mut read).await?;
+            if count == 0 {
+                break;
+            }
+            buffer.extend(&read[..count]);
+
+            let mut start = 0;
+            while let Some(end) = Message::chunk_end(&buffer[start..], max_line) {
+                let chunk = &buffer[start..start + end];
+                Message::chunk_to_erlang(chunk, &create_message, clock, limit, credit).await;
+                start += end;
+            }
+            buffer.drain(..start);
+        }
+
+        // the last line may never be terminated
+        if !buffer.is_empty() {
+            Message::chunk_to_erlang(&buffer, &create_message, clock, limit, credit).await;
         }
         Ok(())
     }
 
-    async fn send_error_to_erlang(error: std::io::Error) {
-        if error.kind() != ErrorKind::UnexpectedEof {
-            let message = format!("{}", error);
-            let _ = Message::Error(message).write_to_erlang();
+    fn chunk_end(buffer: &[u8], max_line: Option<usize>) -> Option<usize> {
+        if buffer.is_empty() {
+            return None;
+        }
+        match max_line {
+            None => Some(buffer.len()),
+            // a newline right after max_line bytes still ends the line
+            Some(max_line) => match buffer.iter().take(max_line + 1).position(|&b| b == b'\n') {
+                Some(newline) => Some(newline + 1),
+                None if buffer.len() > max_line => Some(max_line),
+                None => None,
+            },
+        }
+    }
+
+    async fn chunk_to_erlang<F>(
+        chunk: &[u8],
+        create_message: &F,
+        clock: Option<&Clock>,
+        limit: Option<&OutputLimit>,
+        credit: Option<&Credit>,
+    ) where
+        F: Fn(Vec<u8>) -> Message,
+    {
+        let chunk = match limit {
+            Some(limit) => limit.take(chunk).await,
+            None => chunk,
+        };
+        if chunk.is_empty() {
+            return;
+        }
+
+        let message = match clock {
+            Some(clock) => {
+                let mut bytes = clock.stamp();
+                bytes.extend(chunk);
+                create_message(bytes)
+            }
+            None => create_message(chunk.to_vec()),
+        };
+        // stamped before waiting, as the chunk was already read
+        if let Some(credit) = credit {
+            credit.spend(chunk.len()).await;
         }
+        // keep draining the child while it shuts down, even if erlang is gone
+        let _ = message.try_write_to_erlang().await;
+    }
+
+    fn from_error(error: io::Error) -> Message {
+        match (ProtocolError::from_io(&error), SpawnError::from_io(&error)) {
+            (Some(reason), _) => Message::ProtocolError(reason.to_string()),
+            (_, Some(spawn_error)) => spawn_error.to_message(),
+            _ if error.kind() == ErrorKind::UnexpectedEof => {
+                Message::Error("erlang closed standard input".to_string())
+            }
+            _ => Message::Error(format!("{}", error)),
+        }
+    }
+
+    fn from_panic(panic: Box<dyn std::any::Any + Send>) -> Message {
+        let reason = match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+            (Some(reason), _) => reason.to_string(),
+            (_, Some(reason)) => reason.clone(),
+            _ => "unknown reason".to_string(),
+        };
+        Message::Error(format!("rambo panicked: {}", reason))
     }
 }
 
@@ -155,114 +777,1254 @@ impl std::fmt::Display for Message {
             Message::Stdout(_) => "STDOUT",
             Message::Stderr(_) => "STDERR",
             Message::ExitStatus(_) => "EXIT_STATUS",
+            Message::StdinChunk(_) => "STDIN_CHUNK",
+            Message::StdinClose => "STDIN_CLOSE",
+            Message::Signal(_) => "SIGNAL",
+            Message::KillGrace(_) => "KILL_GRACE",
+            Message::Kill => "KILL",
+            Message::Killed(_) => "KILLED",
+            Message::ExitSignal(_, _) => "EXIT_SIGNAL",
+            Message::Subreaper => "SUBREAPER",
+            Message::Timeout(_) => "TIMEOUT",
+            Message::TimedOut => "TIMED_OUT",
+            Message::Pty(_, _) => "PTY",
+            Message::Resize(_, _) => "RESIZE",
+            Message::Rlimit(_, _) => "RLIMIT",
+            Message::LimitExceeded(_) => "LIMIT_EXCEEDED",
+            Message::Usage(_) => "USAGE",
+            Message::Uid(_) => "UID",
+            Message::Gid(_) => "GID",
+            Message::Groups(_) => "GROUPS",
+            Message::Umask(_) => "UMASK",
+            Message::ProtocolError(_) => "PROTOCOL_ERROR",
+            Message::Hello(_, _) => "HELLO",
+            Message::SpawnError(_, _, _, _) => "SPAWN_ERROR",
+            Message::Pipe => "PIPE",
+            Message::Pipefail => "PIPEFAIL",
+            Message::StageStatuses(_) => "STAGE_STATUSES",
+            Message::StderrMode(_) => "STDERR_MODE",
+            Message::Timestamps => "TIMESTAMPS",
+            Message::LineFraming(_) => "LINE_FRAMING",
+            Message::MaxOutput(_, _, _) => "MAX_OUTPUT",
+            Message::Truncated(_) => "TRUNCATED",
+            Message::OutputLimit => "OUTPUT_LIMIT",
+            Message::Request(_, _) => "REQUEST",
+            Message::Credit(_, _) => "CREDIT",
+            Message::StdinAck => "STDIN_ACK",
         };
         write!(formatter, "{}", name)
     }
 }
 
-async fn receive_command() -> io::Result<(Command, Option<Vec<u8>>)> {
-    let mut program: Option<String> = None;
-    let mut args: Vec<String> = vec![];
+// Stamps each chunk of output with a sequence number shared by stdout and
+// stderr and the nanoseconds since spawning, taken as soon as it is read.
+struct Clock {
+    started: Instant,
+    sequence: Cell<u64>,
+}
+
+impl Clock {
+    fn new(started: Instant) -> Clock {
+        Clock {
+            started,
+            sequence: Cell::new(0),
+        }
+    }
+
+    fn stamp(&self) -> Vec<u8> {
+        let sequence = self.sequence.get();
+        self.sequence.set(sequence + 1);
+        let at = self.started.elapsed().as_nanos() as u64;
+
+        let mut bytes = sequence.to_be_bytes().to_vec();
+        bytes.extend(&at.to_be_bytes());
+        bytes
+    }
+}
+
+// Times are sent in microseconds and memory in bytes.
+#[derive(Debug, Default)]
+struct Usage {
+    user_time: Duration,
+    system_time: Duration,
+    max_rss: u64,
+    minor_faults: u64,
+    major_faults: u64,
+    voluntary_switches: u64,
+    involuntary_switches: u64,
+    wall_time: Duration,
+}
+
+impl Usage {
+    fn to_numbers(&self) -> [u64; 8] {
+        [
+            self.user_time.as_micros() as u64,
+            self.system_time.as_micros() as u64,
+            self.max_rss,
+            self.minor_faults,
+            self.major_faults,
+            self.voluntary_switches,
+            self.involuntary_switches,
+            self.wall_time.as_micros() as u64,
+        ]
+    }
+}
+
+struct Options {
+    programs: Vec<OsString>,
+    input: Option<Vec<u8>>,
+    stream_stdin: bool,
+    kill_grace: Option<Duration>,
+    subreaper: bool,
+    timeout: Option<Duration>,
+    pty: Option<Pty>,
+    pipefail: bool,
+    timestamps: bool,
+    max_line: Option<usize>,
+    max_out: Option<(u64, bool)>,
+    max_err: Option<(u64, bool)>,
+    credit_frames: Option<u64>,
+    credit_bytes: Option<u64>,
+}
+
+#[derive(Clone, Copy)]
+enum Stop {
+    Kill,
+    Timeout,
+    OutputLimit,
+}
+
+// Caps the bytes of one kind of output sent to erlang, shared by every stage
+// of a pipeline. Once exceeded, erlang is told the output was truncated and
+// the command is stopped if asked.
+struct OutputLimit {
+    stream: &'static str,
+    max: u64,
+    sent: Cell<u64>,
+    stop: Option<mpsc::UnboundedSender<Stop>>,
+}
+
+impl OutputLimit {
+    fn new(
+        stream: &'static str,
+        limit: Option<(u64, bool)>,
+        stop: &mpsc::UnboundedSender<Stop>,
+    ) -> Option<OutputLimit> {
+        limit.map(|(max, kill)| OutputLimit {
+            stream,
+            max,
+            sent: Cell::new(0),
+            stop: if kill { Some(stop.clone()) } else { None },
+        })
+    }
+
+    // Returns the part of the chunk still within the limit.
+    async fn take<'a>(&self, chunk: &'a [u8]) -> &'a [u8] {
+        let sent = self.sent.get();
+        let left = self.max.saturating_sub(sent) as usize;
+        self.sent.set(sent.saturating_add(chunk.len() as u64));
+
+        if sent <= self.max && chunk.len() > left {
+            let _ = Message::Truncated(self.stream.to_string())
+                .try_write_to_erlang()
+                .await;
+            if let Some(stop) = &self.stop {
+                let _ = stop.unbounded_send(Stop::OutputLimit);
+            }
+        }
+        &chunk[..chunk.len().min(left)]
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum CreditUnit {
+    Frames,
+    Bytes,
+}
+
+// Output erlang is ready for, in frames, bytes or both, when it asks for
+// flow control. With none left the child is no longer read, so its pipes
+// fill up and it blocks until erlang grants more. Chunks are sent whole
+// while any bytes are left, so bytes run into debt rather than stall on a
+// chunk larger than what is granted.
+struct Credit {
+    frames: Option<Cell<u64>>,
+    bytes: Option<Cell<i64>>,
+    grants: mpsc::UnboundedSender<(CreditUnit, u32)>,
+    granted: Mutex<mpsc::UnboundedReceiver<(CreditUnit, u32)>>,
+}
+
+impl Credit {
+    fn new(frames: Option<u64>, bytes: Option<u64>) -> Option<Credit> {
+        if frames.is_none() && bytes.is_none() {
+            return None;
+        }
+
+        let (grants, granted) = mpsc::unbounded();
+        Some(Credit {
+            frames: frames.map(Cell::new),
+            bytes: bytes.map(|bytes| Cell::new(bytes.min(i64::MAX as u64) as i64)),
+            grants,
+            granted: Mutex::new(granted),
+        })
+    }
+
+    fn grant(&self, unit: CreditUnit, amount: u32) {
+        let _ = self.grants.unbounded_send((unit, amount));
+    }
+
+    // units erlang did not ask for before EOT are never limited
+    fn add(&self, unit: CreditUnit, amount: u32) {
+        match (unit, &self.frames, &self.bytes) {
+            (CreditUnit::Frames, Some(frames), _) => {
+                frames.set(frames.get().saturating_add(u64::from(amount)))
+            }
+            (CreditUnit::Bytes, _, Some(bytes)) => {
+                bytes.set(bytes.get().saturating_add(i64::from(amount)))
+            }
+            _ => (),
+        }
+    }
+
+    fn available(&self) -> bool {
+        self.frames.as_ref().is_none_or(|frames| frames.get() > 0)
+            && self.bytes.as_ref().is_none_or(|bytes| bytes.get() > 0)
+    }
+
+    // Streams waiting together take turns, each grant goes to one of them.
+    async fn spend(&self, length: usize) {
+        loop {
+            if self.available() {
+                if let Some(frames) = &self.frames {
+                    frames.set(frames.get() - 1);
+                }
+                if let Some(bytes) = &self.bytes {
+                    bytes.set(bytes.get() - length as i64);
+                }
+                return;
+            }
+
+            let mut granted = self.granted.lock().await;
+            if !self.available() {
+                if let Some((unit, amount)) = granted.next().await {
+                    self.add(unit, amount);
+                }
+            }
+        }
+    }
+}
+
+enum Exit {
+    Status(ExitStatus),
+    // exited within the grace period after SIGTERM
+    Terminated(ExitStatus, Stop),
+    // exited before the stop, which only killed what it left running
+    Exited(ExitStatus, Stop),
+    // outlived the grace period so it was killed
+    Killed(Stop),
+}
+
+// A command of its own or one stage of a pipeline.
+#[derive(Default)]
+struct Stage {
+    program: Option<OsString>,
+    args: Vec<OsString>,
+    envs: HashMap<OsString, OsString>,
+    current_dir: Option<OsString>,
+}
+
+// Where the standard error of a child goes, unless a terminal already
+// merges it into standard output.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum StderrMode {
+    #[default]
+    Separate,
+    // 2>&1, so both streams share one pipe and keep their order
+    Merge,
+    Discard,
+}
+
+// Applied to every child, whether it runs alone or in a pipeline.
+#[derive(Default)]
+struct ChildOptions {
+    stderr: StderrMode,
+    limits: Vec<(String, u64)>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    groups: Option<Vec<u32>>,
+    umask: Option<u32>,
+}
+
+impl ChildOptions {
+    fn apply(&self, command: &mut Command, terminal: bool) -> io::Result<()> {
+        // lead a new session so the whole process tree can be signalled as a group
+        #[cfg(unix)]
+        unsafe {
+            command.pre_exec(|| {
+                if libc::setsid() == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+
+            if terminal {
+                command.pre_exec(pty::set_controlling_terminal);
+            } else if self.stderr == StderrMode::Merge {
+                // stdout is already in place when hooks run
+                command.pre_exec(|| {
+                    if libc::dup2(1, 2) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    Ok(())
+                });
+            }
+
+            // resolved before spawning, as only setrlimit itself may run after fork
+            let limits = resource_limits(&self.limits)?;
+            if !limits.is_empty() {
+                command.pre_exec(move || set_limits(&limits));
+            }
+        }
+
+        #[cfg(unix)]
+        {
+            let (uid, gid) = (self.uid, self.gid);
+            match self.groups.clone() {
+                // std switches user before pre_exec hooks run, leaving no privilege
+                // to set supplementary groups, so one hook switches everything
+                Some(groups) => unsafe {
+                    command.pre_exec(move || set_credentials(&groups, gid, uid));
+                },
+                None => {
+                    if let Some(gid) = gid {
+                        command.gid(gid);
+                    }
+                    if let Some(uid) = uid {
+                        command.uid(uid);
+                    }
+                }
+            }
+
+            if let Some(umask) = self.umask {
+                unsafe {
+                    command.pre_exec(move || {
+                        libc::umask(umask as libc::mode_t);
+                        Ok(())
+                    });
+                }
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            let shim = std::process::id();
+            unsafe {
+                command.pre_exec(move || owner::die_with_parent(shim));
+            }
+        }
+
+        #[cfg(not(unix))]
+        {
+            let _ = (command, terminal);
+            if self.stderr == StderrMode::Merge {
+                return Err(io::Error::other(
+                    "merging standard error is not supported on this platform",
+                ));
+            }
+            if !self.limits.is_empty() {
+                return Err(io::Error::other(
+                    "resource limits are not supported on this platform",
+                ));
+            }
+            if self.uid.is_some()
+                || self.gid.is_some()
+                || self.groups.is_some()
+                || self.umask.is_some()
+            {
+                return Err(io::Error::other(
+                    "users, groups and umask are not supported on this platform",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Chunks sent before EOT are buffered and keep stdin open after the child
+// spawns, so more chunks can be streamed until STDIN_CLOSE. Each PIPE starts
+// the next stage of a pipeline, which only takes its own command, arguments,
+// environment and directory.
+async fn receive_command() -> io::Result<(Vec<Command>, Options)> {
+    let mut stages: Vec<Stage> = vec![Stage::default()];
     let mut stdin: Option<Vec<u8>> = None;
-    let mut envs: HashMap<String, String> = HashMap::new();
-    let mut current_dir: Option<String> = None;
+    let mut stream_stdin = false;
+    let mut kill_grace: Option<Duration> = None;
+    let mut subreaper = false;
+    let mut timeout: Option<Duration> = None;
+    let mut window_size: Option<(u16, u16)> = None;
+    let mut pipefail = false;
+    let mut timestamps = false;
+    let mut max_line: Option<usize> = None;
+    let mut max_out: Option<(u64, bool)> = None;
+    let mut max_err: Option<(u64, bool)> = None;
+    let mut credit_frames: Option<u64> = None;
+    let mut credit_bytes: Option<u64> = None;
+    let mut child_options = ChildOptions::default();
 
     loop {
+        let stage = stages.last_mut().expect("at least one stage");
         match Message::read_from_erlang().await? {
-            Message::Command(string) => program = Some(string),
-            Message::Arg(string) => args.push(string),
+            Message::Command(string) => stage.program = Some(string),
+            Message::Arg(string) => stage.args.push(string),
             Message::Stdin(bytes) => stdin = Some(bytes),
+            Message::StdinChunk(bytes) => {
+                stdin.get_or_insert_with(Vec::new).extend(bytes);
+                stream_stdin = true;
+            }
             Message::Env(name, value) => {
-                envs.insert(name, value);
+                stage.envs.insert(name, value);
+            }
+            Message::CurrentDir(string) => stage.current_dir = Some(string),
+            Message::KillGrace(milliseconds) => {
+                kill_grace = Some(Duration::from_millis(milliseconds.into()))
+            }
+            Message::Subreaper if in_session() => {
+                return Err(io::Error::other("subreapers are not supported in sessions"))
+            }
+            Message::Subreaper => subreaper = true,
+            Message::Timeout(milliseconds) => {
+                timeout = Some(Duration::from_millis(milliseconds.into()))
+            }
+            Message::Pty(rows, cols) => window_size = Some((rows, cols)),
+            Message::Rlimit(resource, value) => child_options.limits.push((resource, value)),
+            Message::Uid(id) => child_options.uid = Some(id),
+            Message::Gid(id) => child_options.gid = Some(id),
+            Message::Groups(ids) => child_options.groups = Some(ids),
+            Message::Umask(mask) => child_options.umask = Some(mask),
+            Message::Pipe => stages.push(Stage::default()),
+            Message::Pipefail => pipefail = true,
+            Message::StderrMode(mode) => child_options.stderr = mode,
+            Message::Timestamps => timestamps = true,
+            Message::LineFraming(length) => max_line = Some(length as usize),
+            Message::MaxOutput(stream, max, kill) => match stream.as_str() {
+                "stdout" => max_out = Some((max, kill)),
+                "stderr" => max_err = Some((max, kill)),
+                _ => return Err(io::Error::other(format!("unknown stream {}", stream))),
+            },
+            // grants before EOT add up to the credit the command starts with
+            Message::Credit(unit, amount) => {
+                let credit = match unit {
+                    CreditUnit::Frames => &mut credit_frames,
+                    CreditUnit::Bytes => &mut credit_bytes,
+                };
+                *credit = Some(credit.unwrap_or(0) + u64::from(amount));
+            }
+            // erlang decides whether it can work with this shim
+            Message::Hello(_, _) => {
+                Message::Hello(PROTOCOL_VERSION, capabilities())
+                    .write_to_erlang()
+                    .await
             }
-            Message::CurrentDir(string) => current_dir = Some(string),
             Message::Eot => break,
-            message => panic!("unexpected message {}", message),
+            message => return Err(ProtocolError::Unexpected(message.to_string()).into()),
         }
     }
 
-    let mut command = Command::new(program.expect("command required"));
-    command
-        .args(args)
-        .envs(envs)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .kill_on_drop(true);
+    if window_size.is_some() && stages.len() > 1 {
+        return Err(io::Error::other(
+            "pseudo-terminals are not supported in pipelines",
+        ));
+    }
+
+    let last = stages.len() - 1;
+    let mut programs = vec![];
+    let mut commands = vec![];
+    let mut pty = None;
+    let mut next_stdin: Option<std::io::PipeReader> = None;
+
+    for (index, stage) in stages.into_iter().enumerate() {
+        let program = stage.program.ok_or(ProtocolError::MissingCommand)?;
+        let mut command = Command::new(&program);
+        command.args(stage.args).envs(stage.envs).kill_on_drop(true);
 
-    if let Some(current_dir) = current_dir {
-        command.current_dir(current_dir);
+        if let Some(current_dir) = stage.current_dir {
+            command.current_dir(current_dir);
+        }
+
+        match window_size {
+            Some((rows, cols)) => {
+                let (terminal, slave) = Pty::open(rows, cols)?;
+                command
+                    .stdin(slave.try_clone()?)
+                    .stdout(slave.try_clone()?)
+                    .stderr(slave);
+                pty = Some(terminal);
+            }
+            None => {
+                // only the ends of a pipeline are read and written by the shim,
+                // stages in between are connected directly
+                let stdin = next_stdin.take().map_or_else(Stdio::piped, Stdio::from);
+                let stdout = if index == last {
+                    Stdio::piped()
+                } else {
+                    let (reader, writer) = std::io::pipe()?;
+                    next_stdin = Some(reader);
+                    Stdio::from(writer)
+                };
+                let stderr = match child_options.stderr {
+                    StderrMode::Separate => Stdio::piped(),
+                    // replaced by stdout before exec
+                    StderrMode::Merge | StderrMode::Discard => Stdio::null(),
+                };
+                command.stdin(stdin).stdout(stdout).stderr(stderr);
+            }
+        }
+
+        child_options.apply(&mut command, pty.is_some())?;
+        programs.push(program);
+        commands.push(command);
     }
-    Ok((command, stdin))
+
+    let options = Options {
+        programs,
+        input: stdin,
+        stream_stdin,
+        kill_grace,
+        subreaper,
+        timeout,
+        pty,
+        pipefail,
+        timestamps,
+        max_line,
+        max_out,
+        max_err,
+        credit_frames,
+        credit_bytes,
+    };
+    Ok((commands, options))
 }
 
-async fn run_command(mut command: Command, input: Option<Vec<u8>>) -> io::Result<ExitStatus> {
-    let monitor = Message::monitor_erlang().fuse();
+type StreamIn = Box<dyn AsyncWrite + Unpin>;
+type StreamOut = Box<dyn AsyncRead + Unpin>;
+
+fn take_stream<T>(stream: Option<T>, name: &str) -> io::Result<T> {
+    stream.ok_or_else(|| io::Error::other(format!("failed to open child {}", name)))
+}
+
+// Every stage runs at once. The pipeline exits with the status of its last
+// stage, or with pipefail, the last stage that failed.
+async fn run_command(
+    mut commands: Vec<Command>,
+    options: Options,
+) -> io::Result<(Exit, Vec<Option<ExitStatus>>, Usage)> {
+    let (sender, receiver) = if options.stream_stdin {
+        // queued, so messages such as KILL are never stuck behind a child that
+        // stopped reading, and bounded by erlang waiting for STDIN_ACK
+        let (sender, receiver) = mpsc::unbounded();
+        (Some(sender), Some(receiver))
+    } else {
+        (None, None)
+    };
+
+    if options.subreaper {
+        become_subreaper();
+    }
+
+    let started = Instant::now();
+    let mut children = vec![];
+    for (command, program) in commands.iter_mut().zip(&options.programs) {
+        // stages already spawned are killed on drop if this fails
+        let child = command
+            .spawn()
+            .map_err(|error| SpawnError::into_io(program, error))?;
+        children.push(child);
+    }
+    let pids: Vec<u32> = children.iter().map(|child| child.id()).collect();
+    // released once the groups are killed or their leaders reaped
+    let _groups = owner::Groups::guard(&pids);
+
+    let credit = Credit::new(options.credit_frames, options.credit_bytes);
+    let credit = credit.as_ref();
+    let monitor = Message::monitor_erlang(sender, credit).fuse();
     let mut monitor = Box::pin(monitor);
 
-    let mut child = command.spawn()?;
+    // the terminal merges stderr into stdout
+    let (stdin, stdout, stderrs): (StreamIn, StreamOut, Vec<StreamOut>) = match &options.pty {
+        Some(pty) => (Box::new(pty.writer()?), Box::new(pty.reader()?), vec![]),
+        None => {
+            let last = children.len() - 1;
+            let stdin = take_stream(children[0].stdin.take(), "stdin")?;
+            let stdout = take_stream(children[last].stdout.take(), "stdout")?;
+            // only separate stderr is piped to the shim
+            let mut stderrs: Vec<StreamOut> = vec![];
+            for child in children.iter_mut() {
+                if let Some(stderr) = child.stderr.take() {
+                    stderrs.push(Box::new(stderr));
+                }
+            }
+            (Box::new(stdin), Box::new(stdout), stderrs)
+        }
+    };
+
+    // the master only sees EOF once the slave copies held by the command
+    // close, as does every pipe between stages
+    drop(commands);
 
-    let stdin = child
-        .stdin
-        .take()
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "failed to open child stdin"))?;
-    let stdin = Message::stream_to_child(stdin, input).fuse();
+    let terminal = options.pty.is_some();
+    let stdin = Message::stream_to_child(stdin, options.input, receiver, terminal).fuse();
     let mut stdin = Box::pin(stdin);
 
-    let stdout = child
-        .stdout
-        .take()
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "failed to open child stdout"))?;
-    let stdout = Message::stream_to_erlang(stdout, Message::Stdout).fuse();
-    let mut stdout = Box::pin(stdout);
-
-    let stderr = child
-        .stderr
-        .take()
-        .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "failed to open child stderr"))?;
-    let stderr = Message::stream_to_erlang(stderr, Message::Stderr).fuse();
+    let clock = if options.timestamps {
+        Some(Clock::new(started))
+    } else {
+        None
+    };
+
+    let (stop_sender, mut stop_receiver) = mpsc::unbounded();
+    let max_out = OutputLimit::new("stdout", options.max_out, &stop_sender);
+    let max_err = OutputLimit::new("stderr", options.max_err, &stop_sender);
+    drop(stop_sender);
+
+    let clock = clock.as_ref();
+    let max_line = options.max_line;
+    let max_out = max_out.as_ref();
+    let stdout =
+        Message::stream_to_erlang(stdout, Message::Stdout, clock, max_line, max_out, credit);
+    let mut stdout = Box::pin(stdout.fuse());
+
+    // each stream is framed on its own, so lines never mix
+    let max_err = max_err.as_ref();
+    let stderr = stderrs.into_iter().map(|stderr| {
+        Message::stream_to_erlang(stderr, Message::Stderr, clock, max_line, max_err, credit)
+    });
+    let stderr = futures::future::join_all(stderr).fuse();
     let mut stderr = Box::pin(stderr);
 
-    let child = child.fuse();
-    let mut child = Box::pin(child);
+    let mut exits: FuturesUnordered<_> = children
+        .into_iter()
+        .enumerate()
+        .map(|(index, child)| child.map(move |result| (index, result)))
+        .collect();
+
+    let timeout: Fuse<Delay> = match options.timeout {
+        Some(timeout) => tokio::time::delay_for(timeout).fuse(),
+        None => Fuse::terminated(),
+    };
+    let mut timeout = Box::pin(timeout);
+
+    let grace: Fuse<Delay> = Fuse::terminated();
+    let mut grace = Box::pin(grace);
 
-    let mut stdin_done = false;
     let mut stdout_done = false;
     let mut stderr_done = false;
+    let mut statuses: Vec<Option<ExitStatus>> = vec![None; pids.len()];
+    let mut running = pids.len();
+    let mut wait_error: Option<io::Error> = None;
     let mut child_result: Option<io::Result<ExitStatus>> = None;
+    let mut exited: Option<Instant> = None;
+    let mut erlang_error: Option<io::Error> = None;
+    let mut stopping: Option<Stop> = None;
+    let mut terminated = false;
 
-    while !stdin_done || !stdout_done || !stderr_done || child_result.is_none() {
-        futures::select_biased! {
-            error = monitor => return Err(error),
-            result = stdin => stdin_done = true,
-            result = stdout => stdout_done = true,
-            result = stderr => stderr_done = true,
-            result = child => child_result = Some(result),
+    // stdin is not awaited because streamed chunks may never be closed by the
+    // time the child exits
+    while !stdout_done || !stderr_done || child_result.is_none() {
+        let stop = futures::select_biased! {
+            (result, chunks) = monitor => {
+                let stop = match result {
+                    Ok(Message::Signal(name)) => {
+                        for (&pid, status) in pids.iter().zip(&statuses) {
+                            // a reaped pid may have been reused
+                            if status.is_none() {
+                                signal_child(pid, &name);
+                            }
+                        }
+                        None
+                    }
+                    Ok(Message::Kill) => Some(Stop::Kill),
+                    Ok(Message::Resize(rows, cols)) => {
+                        if let Some(pty) = &options.pty {
+                            let _ = pty.resize(rows, cols);
+                        }
+                        None
+                    }
+                    Err(error) => {
+                        erlang_error = Some(error);
+                        Some(Stop::Kill)
+                    }
+                    _ => None,
+                };
+
+                if erlang_error.is_none() {
+                    monitor.set(Message::monitor_erlang(chunks, credit).fuse());
+                }
+                stop
+            }
+            _ = timeout => Some(Stop::Timeout),
+            stop = stop_receiver.select_next_some() => Some(stop),
+            _ = stdin => None,
+            _ = stdout => {
+                stdout_done = true;
+                None
+            }
+            _ = stderr => {
+                stderr_done = true;
+                None
+            }
+            (index, result) = exits.select_next_some() => {
+                match result {
+                    Ok(status) => statuses[index] = Some(status),
+                    Err(error) => wait_error = Some(error),
+                }
+                running -= 1;
+                if running == 0 {
+                    child_result = Some(match wait_error.take() {
+                        Some(error) => Err(error),
+                        None => Ok(pipeline_status(&statuses, options.pipefail)),
+                    });
+                    exited = Some(Instant::now());
+                }
+                None
+            }
+            _ = grace => break,
+        };
+
+        if let (Some(stop), None) = (stop, stopping) {
+            stopping = Some(stop);
+            match options.kill_grace {
+                Some(kill_grace) if child_result.is_none() => {
+                    for (&pid, status) in pids.iter().zip(&statuses) {
+                        if status.is_none() {
+                            signal_group(pid, "SIGTERM");
+                        }
+                    }
+                    terminated = true;
+                    grace.set(tokio::time::delay_for(kill_grace).fuse());
+                }
+                _ => break,
+            }
+        }
+    }
+
+    // the children may be gone but the rest of their trees must not outlive a stop
+    if stopping.is_some() || erlang_error.is_some() || child_result.is_none() {
+        kill_tree(&pids, options.subreaper).await;
+    }
+
+    // reap killed children so their resources are accounted for, which only
+    // happens on Unix as elsewhere they are killed on drop
+    #[cfg(unix)]
+    {
+        if child_result.is_none() {
+            while exits.next().await.is_some() {}
+            exited = Some(Instant::now());
+        }
+    }
+    let wall_time = exited.unwrap_or_else(Instant::now) - started;
+    // children of other requests would be counted too
+    let usage = if in_session() {
+        Usage {
+            wall_time,
+            ..Default::default()
+        }
+    } else {
+        children_usage(wall_time)
+    };
+
+    // erlang is gone or broke the protocol, so the outcome is not reported
+    if let Some(error) = erlang_error {
+        return Err(error);
+    }
+
+    let exit = match (child_result, stopping) {
+        (Some(result), Some(stop)) if terminated => Exit::Terminated(result?, stop),
+        (Some(result), Some(stop)) => Exit::Exited(result?, stop),
+        (Some(result), None) => Exit::Status(result?),
+        (None, stop) => Exit::Killed(stop.unwrap_or(Stop::Kill)),
+    };
+    Ok((exit, statuses, usage))
+}
+
+fn pipeline_status(statuses: &[Option<ExitStatus>], pipefail: bool) -> ExitStatus {
+    let statuses: Vec<ExitStatus> = statuses.iter().flatten().copied().collect();
+    let last = statuses[statuses.len() - 1];
+    if pipefail {
+        statuses
+            .into_iter()
+            .rev()
+            .find(|status| !status.success())
+            .unwrap_or(last)
+    } else {
+        last
+    }
+}
+
+#[cfg(unix)]
+fn signal_number(name: &str) -> Option<libc::c_int> {
+    let signal = match name {
+        "SIGHUP" => libc::SIGHUP,
+        "SIGINT" => libc::SIGINT,
+        "SIGQUIT" => libc::SIGQUIT,
+        "SIGKILL" => libc::SIGKILL,
+        "SIGUSR1" => libc::SIGUSR1,
+        "SIGUSR2" => libc::SIGUSR2,
+        "SIGALRM" => libc::SIGALRM,
+        "SIGTERM" => libc::SIGTERM,
+        "SIGCONT" => libc::SIGCONT,
+        "SIGSTOP" => libc::SIGSTOP,
+        "SIGTSTP" => libc::SIGTSTP,
+        "SIGWINCH" => libc::SIGWINCH,
+        _ => return None,
+    };
+    Some(signal)
+}
+
+#[cfg(unix)]
+fn signal_child(pid: u32, name: &str) {
+    if let Some(signal) = signal_number(name) {
+        unsafe {
+            libc::kill(pid as libc::pid_t, signal);
         }
     }
+}
+
+#[cfg(not(unix))]
+fn signal_child(_pid: u32, _name: &str) {}
 
-    child_result.unwrap()
+// The child leads its own process group, so the group shares its pid.
+#[cfg(unix)]
+fn signal_group(pid: u32, name: &str) {
+    if let Some(signal) = signal_number(name) {
+        unsafe {
+            libc::kill(-(pid as libc::pid_t), signal);
+        }
+    }
 }
 
-async fn run() -> io::Result<()> {
-    let (command, input) = receive_command().await?;
-    let status = run_command(command, input).await?;
+#[cfg(not(unix))]
+fn signal_group(_pid: u32, _name: &str) {}
+
+#[cfg(unix)]
+type Limit = (libc::c_int, libc::rlimit);
+
+#[cfg(unix)]
+fn resource_limits(limits: &[(String, u64)]) -> io::Result<Vec<Limit>> {
+    limits
+        .iter()
+        .map(|(name, value)| {
+            let value = *value;
+            let resource = match name.as_str() {
+                "cpu" => libc::RLIMIT_CPU,
+                "fsize" => libc::RLIMIT_FSIZE,
+                "data" => libc::RLIMIT_DATA,
+                "stack" => libc::RLIMIT_STACK,
+                "core" => libc::RLIMIT_CORE,
+                "nofile" => libc::RLIMIT_NOFILE,
+                "as" => libc::RLIMIT_AS,
+                "nproc" => libc::RLIMIT_NPROC,
+                _ => {
+                    let message = format!("unknown resource limit {}", name);
+                    return Err(io::Error::new(ErrorKind::InvalidInput, message));
+                }
+            };
+            // past the soft limit the kernel sends SIGXCPU, which reports why
+            // the command died, and SIGKILL a second later if it is ignored
+            let hard = match name.as_str() {
+                "cpu" => value.saturating_add(1),
+                _ => value,
+            };
+            let limit = libc::rlimit {
+                rlim_cur: value as libc::rlim_t,
+                rlim_max: hard as libc::rlim_t,
+            };
+            Ok((resource as libc::c_int, limit))
+        })
+        .collect()
+}
+
+#[cfg(unix)]
+fn set_limits(limits: &[Limit]) -> io::Result<()> {
+    for (resource, limit) in limits {
+        if unsafe { libc::setrlimit(*resource as _, limit) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+// Groups go first while the shim may still be privileged.
+#[cfg(unix)]
+fn set_credentials(groups: &[u32], gid: Option<u32>, uid: Option<u32>) -> io::Result<()> {
+    unsafe {
+        if libc::setgroups(groups.len() as _, groups.as_ptr()) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if let Some(gid) = gid {
+            if libc::setgid(gid) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(uid) = uid {
+            if libc::setuid(uid) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Only these limits are enforced with a signal, the rest fail system calls.
+#[cfg(unix)]
+fn exceeded_limit(signal: i32) -> Option<&'static str> {
+    match signal {
+        libc::SIGXCPU => Some("cpu"),
+        libc::SIGXFSZ => Some("fsize"),
+        _ => None,
+    }
+}
+
+#[cfg(not(unix))]
+fn exceeded_limit(_signal: i32) -> Option<&'static str> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn become_subreaper() {
+    unsafe {
+        libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1 as libc::c_ulong);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn become_subreaper() {}
+
+// Descendants that escaped the process group are adopted by the shim as
+// subreaper once their parents die, so hunt those down as well.
+async fn kill_tree(pids: &[u32], subreaper: bool) {
+    for &pid in pids {
+        signal_group(pid, "SIGKILL");
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        if subreaper {
+            for _ in 0..100 {
+                let orphans = adopted_children(pids);
+                if orphans.is_empty() {
+                    break;
+                }
+                for orphan in orphans {
+                    unsafe {
+                        libc::kill(orphan, libc::SIGKILL);
+                        libc::waitpid(orphan, std::ptr::null_mut(), libc::WNOHANG);
+                    }
+                }
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    let _ = subreaper;
+}
+
+#[cfg(target_os = "linux")]
+fn adopted_children(pids: &[u32]) -> Vec<libc::pid_t> {
+    let shim = std::process::id();
+    let mut children = vec![];
+
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return children,
+    };
+
+    for entry in entries.flatten() {
+        let child = match entry.file_name().to_str().map(str::parse::<u32>) {
+            // the stages themselves are reaped by tokio
+            Some(Ok(child)) if !pids.contains(&child) => child,
+            _ => continue,
+        };
+        let stat = match std::fs::read_to_string(entry.path().join("stat")) {
+            Ok(stat) => stat,
+            Err(_) => continue,
+        };
+        // skip the command name as it may contain spaces, then state and ppid follow
+        let ppid = stat
+            .rsplit(')')
+            .next()
+            .and_then(|fields| fields.split_whitespace().nth(1))
+            .and_then(|ppid| ppid.parse::<u32>().ok());
+        if ppid == Some(shim) {
+            children.push(child as libc::pid_t);
+        }
+    }
+
+    children
+}
+
+// The command is the only child, so this covers it and every descendant it
+// waited for.
+#[cfg(unix)]
+fn children_usage(wall_time: Duration) -> Usage {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage) } == -1 {
+        return Usage {
+            wall_time,
+            ..Default::default()
+        };
+    }
+
+    let duration = |time: libc::timeval| {
+        Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+    };
+    // macOS measures in bytes but everyone else in kilobytes
+    let max_rss = if cfg!(target_os = "macos") {
+        usage.ru_maxrss as u64
+    } else {
+        usage.ru_maxrss as u64 * 1024
+    };
+
+    Usage {
+        user_time: duration(usage.ru_utime),
+        system_time: duration(usage.ru_stime),
+        max_rss,
+        minor_faults: usage.ru_minflt as u64,
+        major_faults: usage.ru_majflt as u64,
+        voluntary_switches: usage.ru_nvcsw as u64,
+        involuntary_switches: usage.ru_nivcsw as u64,
+        wall_time,
+    }
+}
+
+#[cfg(not(unix))]
+fn children_usage(wall_time: Duration) -> Usage {
+    Usage {
+        wall_time,
+        ..Default::default()
+    }
+}
+
+// Names the reasons a program commonly fails to spawn the POSIX way.
+#[cfg(unix)]
+fn errno_name(error: &io::Error) -> &'static str {
+    match error.raw_os_error() {
+        Some(libc::EPERM) => "EPERM",
+        Some(libc::ENOENT) => "ENOENT",
+        Some(libc::E2BIG) => "E2BIG",
+        Some(libc::ENOEXEC) => "ENOEXEC",
+        Some(libc::ENOMEM) => "ENOMEM",
+        Some(libc::EACCES) => "EACCES",
+        Some(libc::ENOTDIR) => "ENOTDIR",
+        Some(libc::EISDIR) => "EISDIR",
+        Some(libc::EINVAL) => "EINVAL",
+        Some(libc::ENFILE) => "ENFILE",
+        Some(libc::EMFILE) => "EMFILE",
+        Some(libc::ETXTBSY) => "ETXTBSY",
+        Some(libc::EAGAIN) => "EAGAIN",
+        Some(libc::ELOOP) => "ELOOP",
+        Some(libc::ENAMETOOLONG) => "ENAMETOOLONG",
+        _ => "",
+    }
+}
+
+// Windows error codes have no POSIX names, so guess from the kind.
+#[cfg(not(unix))]
+fn errno_name(error: &io::Error) -> &'static str {
+    match error.kind() {
+        ErrorKind::NotFound => "ENOENT",
+        ErrorKind::PermissionDenied => "EACCES",
+        _ => "",
+    }
+}
+
+#[cfg(unix)]
+fn exit_signal(status: ExitStatus) -> Option<(i32, bool)> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal().map(|signal| (signal, status.core_dumped()))
+}
+
+#[cfg(not(unix))]
+fn exit_signal(_status: ExitStatus) -> Option<(i32, bool)> {
+    None
+}
+
+async fn send_exit_status(status: ExitStatus) {
     if let Some(code) = status.code() {
         Message::ExitStatus(code).write_to_erlang().await;
+    } else if let Some((signal, core_dumped)) = exit_signal(status) {
+        Message::ExitSignal(signal, core_dumped)
+            .write_to_erlang()
+            .await;
+        if let Some(resource) = exceeded_limit(signal) {
+            Message::LimitExceeded(resource.to_string())
+                .write_to_erlang()
+                .await;
+        }
     }
-    Ok(())
+}
+
+// Returns the message that ends the conversation with erlang. Every other
+// message sent is followed by one of these.
+async fn run() -> io::Result<Message> {
+    let (commands, options) = receive_command().await?;
+    let (exit, stages, usage) = run_command(commands, options).await?;
+    let stop = match exit {
+        Exit::Status(status) => {
+            send_exit_status(status).await;
+            None
+        }
+        Exit::Terminated(status, stop) => {
+            send_exit_status(status).await;
+            Message::Killed("SIGTERM".to_string())
+                .write_to_erlang()
+                .await;
+            Some(stop)
+        }
+        Exit::Exited(status, stop) => {
+            send_exit_status(status).await;
+            Message::Killed("SIGKILL".to_string())
+                .write_to_erlang()
+                .await;
+            Some(stop)
+        }
+        Exit::Killed(stop) => {
+            Message::Killed("SIGKILL".to_string())
+                .write_to_erlang()
+                .await;
+            Some(stop)
+        }
+    };
+    if stages.len() > 1 {
+        Message::StageStatuses(stages).write_to_erlang().await;
+    }
+    Message::Usage(usage).write_to_erlang().await;
+
+    match stop {
+        Some(Stop::Timeout) => Ok(Message::TimedOut),
+        Some(Stop::OutputLimit) => Ok(Message::OutputLimit),
+        _ => Ok(Message::Eot),
+    }
+}
+
+// Exactly one terminal message is sent, whatever happens, though erlang may
+// no longer be there to receive it.
+async fn converse() {
+    let message = match AssertUnwindSafe(run()).catch_unwind().await {
+        Ok(Ok(message)) => message,
+        Ok(Err(error)) => Message::from_error(error),
+        Err(panic) => Message::from_panic(panic),
+    };
+    let _ = message.try_write_to_erlang().await;
+}
+
+// One conversation of a session, which only sees frames sent with its id.
+struct Request {
+    id: u32,
+    frames: Mutex<mpsc::UnboundedReceiver<Vec<u8>>>,
+}
+
+tokio::task_local! {
+    static CURRENT_REQUEST: Rc<Request>;
+}
+
+thread_local! {
+    static WRITE_LOCK: Rc<Mutex<()>> = Rc::new(Mutex::new(()));
+}
+
+fn in_session() -> bool {
+    CURRENT_REQUEST.try_with(|_| ()).is_ok()
+}
+
+// Runs many commands at once for erlang. Every frame is a REQUEST wrapping a
+// message with the id of its conversation, which goes exactly as it would
+// for a lone command. A request opens with HELLO under any id not running,
+// so late frames for requests that already ended are dropped. A HELLO outside
+// any request is answered for the session as a whole.
+async fn run_session() {
+    let mut requests: HashMap<u32, mpsc::UnboundedSender<Vec<u8>>> = HashMap::new();
+    let mut running = FuturesUnordered::new();
+
+    let frame = Message::read_frame().fuse();
+    let mut frame = Box::pin(frame);
+
+    loop {
+        futures::select_biased! {
+            id = running.select_next_some() => {
+                requests.remove(&id);
+            }
+            result = frame => {
+                // erlang is gone
+                let bytes = match result {
+                    Ok(bytes) => bytes,
+                    Err(_) => break,
+                };
+
+                let (id, bytes) = match Message::from_bytes(bytes) {
+                    Ok(Message::Request(id, bytes)) => (id, bytes),
+                    // erlang checks the session itself once before any request
+                    Ok(Message::Hello(_, _)) => {
+                        Message::Hello(PROTOCOL_VERSION, capabilities())
+                            .write_to_erlang()
+                            .await;
+                        frame.set(Message::read_frame().fuse());
+                        continue;
+                    }
+                    Ok(message) => {
+                        let error = ProtocolError::Unexpected(message.to_string());
+                        let _ = Message::from_error(error.into()).try_write_to_erlang().await;
+                        break;
+                    }
+                    Err(error) => {
+                        let _ = Message::from_error(error.into()).try_write_to_erlang().await;
+                        break;
+                    }
+                };
+
+                if let Some(sender) = requests.get(&id) {
+                    let _ = sender.unbounded_send(bytes);
+                } else if bytes.first() == Some(&HELLO) {
+                    let (sender, receiver) = mpsc::unbounded();
+                    let _ = sender.unbounded_send(bytes);
+                    requests.insert(id, sender);
+
+                    let request = Rc::new(Request {
+                        id,
+                        frames: Mutex::new(receiver),
+                    });
+                    running.push(CURRENT_REQUEST.scope(request, converse()).map(move |_| id));
+                }
+
+                frame.set(Message::read_frame().fuse());
+            }
+        }
+    }
+
+    // each request stops its command once its frames end
+    drop(requests);
+    while running.next().await.is_some() {}
 }
 
 static mut DEBUG: bool = false;
 
 #[tokio::main(basic_scheduler)]
 async fn main() {
+    if std::env::args().skip(1).any(|arg| arg == "--guard") {
+        owner::guard();
+        return;
+    }
+
     unsafe {
         DEBUG = std::env::var_os("RAMBO_DEBUG").is_some();
     }
+    owner::watch();
 
-    match run().await {
-        Ok(()) => Message::Eot.write_to_erlang().await,
-        Err(error) => Message::send_error_to_erlang(error).await,
-    };
+    if std::env::args().skip(1).any(|arg| arg == "--session") {
+        run_session().await;
+    } else {
+        converse().await;
+    }
+
+    // don't let shutdown wait on a blocking read from erlang that may never end
+    std::process::exit(0);
 }
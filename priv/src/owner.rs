@@ -0,0 +1,228 @@
+// Erlang stops the shim by closing its standard input, which never happens if
+// the shim or erlang is killed outright. On Linux, children die with the shim,
+// a guard kills the rest of their process groups and the shim notices its
+// owner dying, so none of them outlives it.
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use futures::future::{self, FutureExt, LocalBoxFuture, Shared};
+    use mio::unix::EventedFd;
+    use mio::{Evented, Poll, PollOpt, Ready, Token};
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+    use std::fs::File;
+    use std::io::{self, BufRead, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+    use std::process::{ChildStdin, Command, Stdio};
+    use tokio::io::PollEvented;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    thread_local! {
+        static GONE: RefCell<Option<Shared<LocalBoxFuture<'static, ()>>>> = RefCell::new(None);
+        static GUARD: RefCell<Option<ChildStdin>> = const { RefCell::new(None) };
+    }
+
+    // Asks for SIGTERM when the parent of the shim dies and, given the pid of
+    // erlang in RAMBO_OWNER, watches it exit as well. Either way the owner is
+    // gone, as if it had closed standard input.
+    pub fn watch() {
+        let owner = std::env::var("RAMBO_OWNER")
+            .ok()
+            .and_then(|pid| pid.parse::<libc::pid_t>().ok());
+        // commands must not inherit it
+        std::env::remove_var("RAMBO_OWNER");
+
+        // listen before asking, so the signal is never fatal
+        let mut terminated = match signal(SignalKind::terminate()) {
+            Ok(terminated) => terminated,
+            Err(_) => return,
+        };
+        unsafe {
+            let parent = libc::getppid();
+            libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM as libc::c_ulong);
+            // the parent died before the shim asked
+            if libc::getppid() != parent {
+                libc::raise(libc::SIGTERM);
+            }
+        }
+
+        let terminated = async move {
+            terminated.recv().await;
+        };
+        let exited = async move {
+            match owner {
+                Some(owner) if exited(owner).await.is_ok() => (),
+                // kernels before 5.3 cannot watch a process that is not a child
+                _ => future::pending().await,
+            }
+        };
+        let gone = future::select(terminated.boxed_local(), exited.boxed_local())
+            .map(|_| ())
+            .boxed_local()
+            .shared();
+        GONE.with(|cell| *cell.borrow_mut() = Some(gone));
+    }
+
+    // Never resolves unless watch was called and the owner is gone.
+    pub fn gone() -> LocalBoxFuture<'static, ()> {
+        match GONE.with(|cell| cell.borrow().clone()) {
+            Some(gone) => gone.boxed_local(),
+            None => future::pending().boxed_local(),
+        }
+    }
+
+    async fn exited(pid: libc::pid_t) -> io::Result<()> {
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // a pidfd becomes readable once its process exits
+        let pidfd = PollEvented::new(Pidfd(unsafe { File::from_raw_fd(fd as RawFd) }))?;
+        future::poll_fn(|cx| pidfd.poll_read_ready(cx, Ready::readable())).await?;
+        Ok(())
+    }
+
+    // Run in the child before exec, last as changing credentials clears it.
+    // The signal is sent when the thread that forked dies, not the shim, so
+    // this relies on spawn() running on the main runtime thread. Tokio's
+    // blocking pool reads stdin on threads that come and go, so spawning
+    // must never move onto one of those or another worker thread.
+    pub fn die_with_parent(parent: u32) -> io::Result<()> {
+        unsafe {
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL as libc::c_ulong) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            // the shim died before the child asked
+            if libc::getppid() as u32 != parent {
+                libc::raise(libc::SIGKILL);
+            }
+        }
+        Ok(())
+    }
+
+    // Process groups of commands, which the guard kills if the shim dies before
+    // they are dropped.
+    pub struct Groups(Vec<u32>);
+
+    impl Groups {
+        pub fn guard(pids: &[u32]) -> Groups {
+            tell_guard('+', pids);
+            Groups(pids.to_vec())
+        }
+    }
+
+    impl Drop for Groups {
+        fn drop(&mut self) {
+            tell_guard('-', &self.0);
+        }
+    }
+
+    // The guard is started with the first command and reads a line per group,
+    // such as "+123" and later "-123".
+    fn tell_guard(change: char, pids: &[u32]) {
+        GUARD.with(|cell| {
+            let mut guard = cell.borrow_mut();
+            if guard.is_none() {
+                *guard = spawn_guard().ok();
+            }
+            if let Some(pipe) = guard.as_mut() {
+                for pid in pids {
+                    let _ = writeln!(pipe, "{}{}", change, pid);
+                }
+            }
+        });
+    }
+
+    // The pipe is closed on exec, so only the shim ever holds it open.
+    fn spawn_guard() -> io::Result<ChildStdin> {
+        let mut guard = Command::new(std::env::current_exe()?)
+            .arg("--guard")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        Ok(guard.stdin.take().expect("guard stdin is piped"))
+    }
+
+    // Runs as the guard. Children die with the shim but not theirs, so once the
+    // pipe closes, whether the shim exited or was killed, whatever is left in
+    // their groups is killed.
+    pub fn guard() {
+        let mut groups = HashSet::new();
+        for line in io::stdin().lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            let pid = |line: &str| line.parse::<libc::pid_t>().ok();
+            if let Some(pid) = line.strip_prefix('+').and_then(pid) {
+                groups.insert(pid);
+            } else if let Some(pid) = line.strip_prefix('-').and_then(pid) {
+                groups.remove(&pid);
+            }
+        }
+        for group in groups {
+            unsafe {
+                libc::kill(-group, libc::SIGKILL);
+            }
+        }
+    }
+
+    struct Pidfd(File);
+
+    impl Evented for Pidfd {
+        fn register(
+            &self,
+            poll: &Poll,
+            token: Token,
+            interest: Ready,
+            opts: PollOpt,
+        ) -> io::Result<()> {
+            EventedFd(&self.0.as_raw_fd()).register(poll, token, interest, opts)
+        }
+
+        fn reregister(
+            &self,
+            poll: &Poll,
+            token: Token,
+            interest: Ready,
+            opts: PollOpt,
+        ) -> io::Result<()> {
+            EventedFd(&self.0.as_raw_fd()).reregister(poll, token, interest, opts)
+        }
+
+        fn deregister(&self, poll: &Poll) -> io::Result<()> {
+            EventedFd(&self.0.as_raw_fd()).deregister(poll)
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use linux::{die_with_parent, gone, guard, watch, Groups};
+
+// Elsewhere only standard input closing stops the shim.
+#[cfg(not(target_os = "linux"))]
+mod unsupported {
+    use futures::future::{self, FutureExt, LocalBoxFuture};
+
+    pub fn watch() {
+        std::env::remove_var("RAMBO_OWNER");
+    }
+
+    pub fn gone() -> LocalBoxFuture<'static, ()> {
+        future::pending().boxed_local()
+    }
+
+    pub struct Groups;
+
+    impl Groups {
+        pub fn guard(_pids: &[u32]) -> Groups {
+            Groups
+        }
+    }
+
+    pub fn guard() {}
+}
+
+#[cfg(not(target_os = "linux"))]
+pub use unsupported::{gone, guard, watch, Groups};
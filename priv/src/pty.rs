@@ -0,0 +1,181 @@
+#[cfg(unix)]
+mod unix {
+    use mio::unix::EventedFd;
+    use mio::{Evented, Poll, PollOpt, Ready, Token};
+    use std::fs::File;
+    use std::io::{self, Read, Write};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+    use tokio::io::PollEvented;
+
+    pub struct Pty {
+        master: File,
+    }
+
+    impl Pty {
+        // Returns the master side for the shim and the slave side for the child
+        pub fn open(rows: u16, cols: u16) -> io::Result<(Pty, File)> {
+            let mut master: libc::c_int = -1;
+            let mut slave: libc::c_int = -1;
+            let mut size = window_size(rows, cols);
+
+            // macOS declares the window size mutable
+            #[allow(clippy::unnecessary_mut_passed)]
+            let result = unsafe {
+                libc::openpty(
+                    &mut master,
+                    &mut slave,
+                    std::ptr::null_mut(),
+                    std::ptr::null_mut(),
+                    &mut size,
+                )
+            };
+            if result == -1 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let master = unsafe { File::from_raw_fd(master) };
+            let slave = unsafe { File::from_raw_fd(slave) };
+
+            // neither side may leak into the child beyond its standard streams
+            set_flag(&master, libc::F_GETFD, libc::F_SETFD, libc::FD_CLOEXEC)?;
+            set_flag(&slave, libc::F_GETFD, libc::F_SETFD, libc::FD_CLOEXEC)?;
+            set_flag(&master, libc::F_GETFL, libc::F_SETFL, libc::O_NONBLOCK)?;
+
+            Ok((Pty { master }, slave))
+        }
+
+        pub fn reader(&self) -> io::Result<PollEvented<Master>> {
+            PollEvented::new(Master(self.master.try_clone()?))
+        }
+
+        pub fn writer(&self) -> io::Result<PollEvented<Master>> {
+            PollEvented::new(Master(self.master.try_clone()?))
+        }
+
+        pub fn resize(&self, rows: u16, cols: u16) -> io::Result<()> {
+            let size = window_size(rows, cols);
+            let result =
+                unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCSWINSZ as _, &size) };
+            if result == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+
+    // Makes the slave on stdin the controlling terminal of a session leader
+    pub fn set_controlling_terminal() -> io::Result<()> {
+        if unsafe { libc::ioctl(0, libc::TIOCSCTTY as _, 0) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn window_size(rows: u16, cols: u16) -> libc::winsize {
+        libc::winsize {
+            ws_row: rows,
+            ws_col: cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        }
+    }
+
+    fn set_flag(
+        file: &File,
+        get: libc::c_int,
+        set: libc::c_int,
+        flag: libc::c_int,
+    ) -> io::Result<()> {
+        let fd = file.as_raw_fd();
+        unsafe {
+            let flags = libc::fcntl(fd, get);
+            if flags == -1 || libc::fcntl(fd, set, flags | flag) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    pub struct Master(File);
+
+    impl Read for Master {
+        fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buffer) {
+                // every slave closed, which is how the master sees EOF
+                Err(error) if error.raw_os_error() == Some(libc::EIO) => Ok(0),
+                result => result,
+            }
+        }
+    }
+
+    impl Write for Master {
+        fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+            self.0.write(buffer)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.flush()
+        }
+    }
+
+    impl Evented for Master {
+        fn register(
+            &self,
+            poll: &Poll,
+            token: Token,
+            interest: Ready,
+            opts: PollOpt,
+        ) -> io::Result<()> {
+            EventedFd(&self.0.as_raw_fd()).register(poll, token, interest, opts)
+        }
+
+        fn reregister(
+            &self,
+            poll: &Poll,
+            token: Token,
+            interest: Ready,
+            opts: PollOpt,
+        ) -> io::Result<()> {
+            EventedFd(&self.0.as_raw_fd()).reregister(poll, token, interest, opts)
+        }
+
+        fn deregister(&self, poll: &Poll) -> io::Result<()> {
+            EventedFd(&self.0.as_raw_fd()).deregister(poll)
+        }
+    }
+}
+
+#[cfg(unix)]
+pub use unix::{set_controlling_terminal, Pty};
+
+// Pseudo-terminals only exist on Unix, so this Pty can never be constructed.
+#[cfg(not(unix))]
+mod unsupported {
+    use std::fs::File;
+    use std::io;
+
+    pub enum Pty {}
+
+    impl Pty {
+        pub fn open(_rows: u16, _cols: u16) -> io::Result<(Pty, File)> {
+            Err(io::Error::other(
+                "pseudo-terminals are not supported on this platform",
+            ))
+        }
+
+        pub fn reader(&self) -> io::Result<tokio::io::Empty> {
+            match *self {}
+        }
+
+        pub fn writer(&self) -> io::Result<tokio::io::Sink> {
+            match *self {}
+        }
+
+        pub fn resize(&self, _rows: u16, _cols: u16) -> io::Result<()> {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub use unsupported::Pty;
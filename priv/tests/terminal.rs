@@ -0,0 +1,503 @@
+// Every conversation with the shim must end with exactly one terminal
+// message, whether the command succeeds or anything along the way fails.
+
+use std::io::{Read, Write};
+use std::process::{Child, Command, Stdio};
+
+const COMMAND: u8 = 0;
+const ARG: u8 = 1;
+const EOT: u8 = 5;
+const ERROR: u8 = 6;
+const STDOUT: u8 = 7;
+const STDERR: u8 = 8;
+const EXIT_STATUS: u8 = 9;
+const STDIN_CHUNK: u8 = 10;
+const STDIN_CLOSE: u8 = 11;
+const SIGNAL: u8 = 12;
+const KILL: u8 = 14;
+const KILLED: u8 = 15;
+const TIMEOUT: u8 = 18;
+const TIMED_OUT: u8 = 19;
+const RLIMIT: u8 = 22;
+const PROTOCOL_ERROR: u8 = 29;
+const HELLO: u8 = 30;
+const SPAWN_ERROR: u8 = 31;
+const PIPE: u8 = 32;
+const STAGE_STATUSES: u8 = 34;
+const STDERR_MODE: u8 = 35;
+const LINE_FRAMING: u8 = 37;
+const MAX_OUTPUT: u8 = 38;
+const TRUNCATED: u8 = 39;
+const OUTPUT_LIMIT: u8 = 40;
+const REQUEST: u8 = 41;
+const CREDIT: u8 = 42;
+const STDIN_ACK: u8 = 43;
+
+const TERMINALS: [u8; 6] = [
+    EOT,
+    ERROR,
+    TIMED_OUT,
+    PROTOCOL_ERROR,
+    SPAWN_ERROR,
+    OUTPUT_LIMIT,
+];
+
+fn frame(tag: u8, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = ((1 + payload.len()) as u32).to_be_bytes().to_vec();
+    bytes.push(tag);
+    bytes.extend(payload);
+    bytes
+}
+
+fn command(program: &str, args: &[&str]) -> Vec<Vec<u8>> {
+    let mut frames = vec![frame(COMMAND, program.as_bytes())];
+    for arg in args {
+        frames.push(frame(ARG, arg.as_bytes()));
+    }
+    frames
+}
+
+fn spawn() -> Child {
+    spawn_with(&[])
+}
+
+fn spawn_with(args: &[&str]) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_rambo"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to spawn shim")
+}
+
+fn send(shim: &mut Child, frames: &[Vec<u8>]) {
+    let stdin = shim.stdin.as_mut().unwrap();
+    for frame in frames {
+        stdin.write_all(frame).unwrap();
+    }
+    stdin.flush().unwrap();
+}
+
+// Reads until the shim exits, so anything sent after the terminal shows up.
+fn receive(mut shim: Child) -> Vec<(u8, Vec<u8>)> {
+    let mut output = vec![];
+    shim.stdout
+        .take()
+        .unwrap()
+        .read_to_end(&mut output)
+        .unwrap();
+    assert!(shim.wait().unwrap().success());
+
+    let mut frames = vec![];
+    let mut rest = output.as_slice();
+    while !rest.is_empty() {
+        let mut length = [0; 4];
+        length.copy_from_slice(&rest[..4]);
+        let length = u32::from_be_bytes(length) as usize;
+        frames.push((rest[4], rest[5..4 + length].to_vec()));
+        rest = &rest[4 + length..];
+    }
+    frames
+}
+
+fn run(frames: &[Vec<u8>]) -> Vec<(u8, Vec<u8>)> {
+    let mut shim = spawn();
+    send(&mut shim, frames);
+    receive(shim)
+}
+
+fn assert_terminal(frames: &[(u8, Vec<u8>)], tag: u8) -> Vec<u8> {
+    let terminals = frames
+        .iter()
+        .filter(|(tag, _)| TERMINALS.contains(tag))
+        .count();
+    assert_eq!(terminals, 1, "expected one terminal in {:?}", frames);
+
+    let (last, payload) = frames.last().unwrap();
+    assert_eq!(*last, tag, "expected terminal {} in {:?}", tag, frames);
+    payload.clone()
+}
+
+#[cfg(unix)]
+#[test]
+fn exit() {
+    let mut frames = command("/bin/sh", &["-c", "echo rambo; exit 3"]);
+    frames.push(frame(EOT, &[]));
+
+    let frames = run(&frames);
+    assert_terminal(&frames, EOT);
+    assert!(frames.contains(&(STDOUT, b"rambo\n".to_vec())));
+    assert!(frames.contains(&(EXIT_STATUS, 3i32.to_be_bytes().to_vec())));
+}
+
+#[test]
+fn hello() {
+    let mut frames = vec![frame(HELLO, &[0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0])];
+    frames.extend(command("rambo-does-not-exist", &[]));
+    frames.push(frame(EOT, &[]));
+
+    let frames = run(&frames);
+    assert_eq!(frames[0].0, HELLO);
+    assert_terminal(&frames, SPAWN_ERROR);
+}
+
+#[test]
+fn spawn_error() {
+    let mut frames = command("rambo-does-not-exist", &[]);
+    frames.push(frame(EOT, &[]));
+
+    let payload = assert_terminal(&run(&frames), SPAWN_ERROR);
+    assert!(payload.ends_with(b"NotFound\x00\x00\x00\x06ENOENTrambo-does-not-exist"));
+}
+
+#[test]
+fn empty_message() {
+    let payload = assert_terminal(&run(&[vec![0, 0, 0, 0]]), PROTOCOL_ERROR);
+    assert_eq!(payload, b"empty message");
+}
+
+#[test]
+fn unknown_message() {
+    let payload = assert_terminal(&run(&[frame(255, &[])]), PROTOCOL_ERROR);
+    assert_eq!(payload, b"unknown message 255");
+}
+
+#[test]
+fn malformed_message() {
+    let payload = assert_terminal(&run(&[frame(TIMEOUT, &[1])]), PROTOCOL_ERROR);
+    assert_eq!(payload, b"malformed message 18");
+}
+
+#[cfg(unix)]
+#[test]
+fn unknown_signal() {
+    let mut frames = command("sleep", &["10"]);
+    frames.push(frame(EOT, &[]));
+    frames.push(frame(SIGNAL, b"SIGBOGUS"));
+
+    let payload = assert_terminal(&run(&frames), PROTOCOL_ERROR);
+    assert_eq!(payload, b"malformed message 12");
+}
+
+#[test]
+fn unexpected_message() {
+    let payload = assert_terminal(&run(&[frame(KILL, &[])]), PROTOCOL_ERROR);
+    assert_eq!(payload, b"unexpected message KILL");
+}
+
+#[test]
+fn missing_command() {
+    let payload = assert_terminal(&run(&[frame(EOT, &[])]), PROTOCOL_ERROR);
+    assert_eq!(payload, b"command required");
+}
+
+#[cfg(unix)]
+#[test]
+fn invalid_option() {
+    let mut frames = command("true", &[]);
+    frames.push(frame(RLIMIT, b"\x00\x00\x00\x00\x00\x00\x00\x01bogus"));
+    frames.push(frame(EOT, &[]));
+
+    let payload = assert_terminal(&run(&frames), ERROR);
+    assert_eq!(payload, b"unknown resource limit bogus");
+}
+
+#[test]
+fn closed_before_command() {
+    let mut shim = spawn();
+    send(&mut shim, &command("echo", &[]));
+    drop(shim.stdin.take());
+
+    assert_terminal(&receive(shim), ERROR);
+}
+
+#[cfg(unix)]
+#[test]
+fn closed_while_running() {
+    let mut frames = command("sleep", &["10"]);
+    frames.push(frame(EOT, &[]));
+
+    let mut shim = spawn();
+    send(&mut shim, &frames);
+    drop(shim.stdin.take());
+
+    assert_terminal(&receive(shim), ERROR);
+}
+
+#[cfg(unix)]
+#[test]
+fn timed_out() {
+    let mut frames = command("sleep", &["10"]);
+    frames.push(frame(TIMEOUT, &100u32.to_be_bytes()));
+    frames.push(frame(EOT, &[]));
+
+    let frames = run(&frames);
+    assert_terminal(&frames, TIMED_OUT);
+    assert!(frames.contains(&(KILLED, b"SIGKILL".to_vec())));
+}
+
+#[cfg(unix)]
+#[test]
+fn timed_out_after_exit() {
+    // the background sleep holds stdout open after the shell exits
+    let mut frames = command("sh", &["-c", "sleep 10 & exit 0"]);
+    frames.push(frame(TIMEOUT, &300u32.to_be_bytes()));
+    frames.push(frame(EOT, &[]));
+
+    let frames = run(&frames);
+    assert_terminal(&frames, TIMED_OUT);
+    assert!(frames.contains(&(EXIT_STATUS, 0i32.to_be_bytes().to_vec())));
+    assert!(frames.contains(&(KILLED, b"SIGKILL".to_vec())));
+    assert!(!frames.contains(&(KILLED, b"SIGTERM".to_vec())));
+}
+
+#[cfg(unix)]
+#[test]
+fn killed() {
+    let mut frames = command("sleep", &["10"]);
+    frames.push(frame(EOT, &[]));
+    frames.push(frame(KILL, &[]));
+
+    let frames = run(&frames);
+    assert_terminal(&frames, EOT);
+    assert!(frames.contains(&(KILLED, b"SIGKILL".to_vec())));
+}
+
+#[cfg(unix)]
+#[test]
+fn pipeline() {
+    let mut frames = command("/bin/sh", &["-c", "echo rambo; exit 3"]);
+    frames.push(frame(PIPE, &[]));
+    frames.extend(command("cat", &[]));
+    frames.push(frame(EOT, &[]));
+
+    let frames = run(&frames);
+    assert_terminal(&frames, EOT);
+    assert!(frames.contains(&(STDOUT, b"rambo\n".to_vec())));
+    assert!(frames.contains(&(STAGE_STATUSES, vec![0, 0, 0, 0, 3, 0, 0, 0, 0, 0])));
+}
+
+#[cfg(unix)]
+#[test]
+fn merged_stderr() {
+    let mut frames = command("/bin/sh", &["-c", "echo 1; echo 2 >&2; echo 3"]);
+    frames.push(frame(STDERR_MODE, &[1]));
+    frames.push(frame(EOT, &[]));
+
+    let frames = run(&frames);
+    assert_terminal(&frames, EOT);
+    let out: Vec<u8> = frames
+        .iter()
+        .filter(|(tag, _)| *tag == STDOUT)
+        .flat_map(|(_, data)| data.clone())
+        .collect();
+    assert_eq!(out, b"1\n2\n3\n");
+    assert!(!frames.iter().any(|(tag, _)| *tag == STDERR));
+}
+
+#[cfg(unix)]
+#[test]
+fn output_limit() {
+    let mut frames = command("yes", &[]);
+    frames.push(frame(
+        MAX_OUTPUT,
+        b"\x00\x00\x00\x00\x00\x00\x00\x0a\x01stdout",
+    ));
+    frames.push(frame(EOT, &[]));
+
+    let frames = run(&frames);
+    assert_terminal(&frames, OUTPUT_LIMIT);
+    assert!(frames.contains(&(STDOUT, b"y\ny\ny\ny\ny\n".to_vec())));
+    assert!(frames.contains(&(TRUNCATED, b"stdout".to_vec())));
+}
+
+#[cfg(unix)]
+#[test]
+fn credit() {
+    let mut frames = command("seq", &["1", "1000000"]);
+    frames.push(frame(LINE_FRAMING, &100u32.to_be_bytes()));
+    frames.push(frame(CREDIT, &[0, 0, 0, 0, 2]));
+    frames.push(frame(EOT, &[]));
+
+    let mut shim = spawn();
+    send(&mut shim, &frames);
+    // seq blocks on its full pipe until killed, so no more lines are sent
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    send(&mut shim, &[frame(KILL, &[])]);
+
+    let frames = receive(shim);
+    assert_terminal(&frames, EOT);
+    let lines: Vec<&[u8]> = frames
+        .iter()
+        .filter(|(tag, _)| *tag == STDOUT)
+        .map(|(_, line)| line.as_slice())
+        .collect();
+    assert_eq!(lines, [b"1\n", b"2\n"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn line_framing() {
+    let mut frames = command("printf", &["abcd\\nefghi\\n"]);
+    frames.push(frame(LINE_FRAMING, &4u32.to_be_bytes()));
+    frames.push(frame(EOT, &[]));
+
+    let frames = run(&frames);
+    assert_terminal(&frames, EOT);
+    let lines: Vec<&[u8]> = frames
+        .iter()
+        .filter(|(tag, _)| *tag == STDOUT)
+        .map(|(_, line)| line.as_slice())
+        .collect();
+    assert_eq!(lines, [&b"abcd\n"[..], b"efgh", b"i\n"]);
+}
+
+#[test]
+fn stdin_ack() {
+    let mut frames = command("cat", &[]);
+    // an empty chunk keeps stdin open after EOT
+    frames.push(frame(STDIN_CHUNK, &[]));
+    frames.push(frame(EOT, &[]));
+    frames.push(frame(STDIN_CHUNK, b"ram"));
+    frames.push(frame(STDIN_CHUNK, b"bo"));
+    frames.push(frame(STDIN_CLOSE, &[]));
+
+    let frames = run(&frames);
+    assert_terminal(&frames, EOT);
+    let acks = frames.iter().filter(|(tag, _)| *tag == STDIN_ACK).count();
+    assert_eq!(acks, 2);
+}
+
+// Killed outright, the shim sends no terminal and cannot kill its child, so
+// the child dies with it.
+#[cfg(target_os = "linux")]
+#[test]
+fn shim_killed() {
+    let mut frames = command("/bin/sh", &["-c", "echo $$; exec sleep 10"]);
+    frames.push(frame(EOT, &[]));
+
+    let mut shim = spawn();
+    send(&mut shim, &frames);
+
+    let (tag, pid) = receive_frame(shim.stdout.as_mut().unwrap());
+    assert_eq!(tag, STDOUT);
+
+    shim.kill().unwrap();
+    shim.wait().unwrap();
+    assert_gone(&String::from_utf8(pid).unwrap());
+}
+
+// Only the child dies with the shim, the guard kills the rest of its group.
+#[cfg(target_os = "linux")]
+#[test]
+fn shim_killed_with_grandchild() {
+    let mut frames = command("/bin/sh", &["-c", "sleep 10 & echo $!; wait"]);
+    frames.push(frame(EOT, &[]));
+
+    let mut shim = spawn();
+    send(&mut shim, &frames);
+
+    let (tag, pid) = receive_frame(shim.stdout.as_mut().unwrap());
+    assert_eq!(tag, STDOUT);
+
+    shim.kill().unwrap();
+    shim.wait().unwrap();
+    assert_gone(&String::from_utf8(pid).unwrap());
+}
+
+// Erlang may go away while stdin is streamed, which still stops the command
+// and everything it started.
+#[cfg(unix)]
+#[test]
+fn closed_while_streaming() {
+    let mut frames = command("/bin/sh", &["-c", "sleep 10 & echo $!; cat"]);
+    frames.push(frame(STDIN_CHUNK, &[]));
+    frames.push(frame(EOT, &[]));
+
+    let mut shim = spawn();
+    send(&mut shim, &frames);
+
+    let mut stdout = shim.stdout.take().unwrap();
+    let (tag, pid) = receive_frame(&mut stdout);
+    assert_eq!(tag, STDOUT);
+    // nobody reads the ack for this chunk
+    drop(stdout);
+    send(&mut shim, &[frame(STDIN_CHUNK, b"rambo\n")]);
+    std::thread::sleep(std::time::Duration::from_millis(200));
+
+    drop(shim.stdin.take());
+    assert!(shim.wait().unwrap().success());
+    assert_gone(&String::from_utf8(pid).unwrap());
+}
+
+fn receive_frame(stdout: &mut impl Read) -> (u8, Vec<u8>) {
+    let mut length = [0; 4];
+    stdout.read_exact(&mut length).unwrap();
+    let mut payload = vec![0; u32::from_be_bytes(length) as usize];
+    stdout.read_exact(&mut payload).unwrap();
+    (payload[0], payload[1..].to_vec())
+}
+
+fn assert_gone(pid: &str) {
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    // a dead process may linger unreaped
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid.trim()));
+    assert!(stat.is_err() || stat.unwrap().contains(") Z "));
+}
+
+fn request(id: u32, frame: &[u8]) -> Vec<u8> {
+    let mut payload = id.to_be_bytes().to_vec();
+    payload.extend(&frame[4..]);
+    self::frame(REQUEST, &payload)
+}
+
+#[cfg(unix)]
+#[test]
+fn session() {
+    let hello = frame(HELLO, &[0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let mut frames = vec![];
+    // ids may open in any order
+    for (id, program) in [(2, "true"), (1, "sleep"), (3, "rambo-does-not-exist")] {
+        frames.push(request(id, &hello));
+        frames.push(request(id, &frame(COMMAND, program.as_bytes())));
+    }
+    frames.push(request(1, &frame(ARG, b"10")));
+    for id in 1..=3 {
+        frames.push(request(id, &frame(EOT, &[])));
+    }
+    // requests that never opened are ignored
+    frames.push(request(4, &frame(KILL, &[])));
+    frames.push(request(1, &frame(KILL, &[])));
+
+    let mut shim = spawn_with(&["--session"]);
+    send(&mut shim, std::slice::from_ref(&hello));
+    let (tag, _capabilities) = receive_frame(shim.stdout.as_mut().unwrap());
+    assert_eq!(tag, HELLO);
+    send(&mut shim, &frames);
+
+    // closing the session early would end running requests with an error
+    let mut stdout = shim.stdout.take().unwrap();
+    let mut requests: Vec<Vec<(u8, Vec<u8>)>> = vec![vec![]; 3];
+    let mut terminals = 0;
+    while terminals < 3 {
+        let mut length = [0; 4];
+        stdout.read_exact(&mut length).unwrap();
+        let mut payload = vec![0; u32::from_be_bytes(length) as usize];
+        stdout.read_exact(&mut payload).unwrap();
+
+        assert_eq!(payload[0], REQUEST);
+        let id = u32::from_be_bytes([payload[1], payload[2], payload[3], payload[4]]);
+        assert!((1..=3).contains(&id), "frame for request {}", id);
+        requests[id as usize - 1].push((payload[5], payload[6..].to_vec()));
+        if TERMINALS.contains(&payload[5]) {
+            terminals += 1;
+        }
+    }
+    drop(shim.stdin.take());
+    assert!(shim.wait().unwrap().success());
+    assert_terminal(&requests[0], EOT);
+    assert!(requests[0].contains(&(KILLED, b"SIGKILL".to_vec())));
+    assert_terminal(&requests[1], EOT);
+    assert_terminal(&requests[2], SPAWN_ERROR);
+}
@@ -10,15 +10,77 @@ defmodule RamboTest do
     assert {:ok, %{status: 0, out: "rambo"}} = Rambo.run("cat", in: "rambo")
   end
 
+  test "streaming standard input" do
+    stream = Stream.map(1..3, &Integer.to_string/1)
+    assert {:ok, %{status: 0, out: "123"}} = Rambo.run("cat", in: stream)
+    assert {:ok, %{status: 0, out: "1"}} = Rambo.run("head", ["-c", "1"], in: Stream.cycle(["1"]))
+
+    # more chunks than rambo takes before acking them
+    stream = Stream.map(1..100, &"#{&1}\n")
+    assert {:ok, %{status: 0, out: out}} = Rambo.run("cat", in: stream)
+    assert out |> String.split("\n", trim: true) |> length() == 100
+  end
+
   test "standard error" do
     assert {:error, %{status: 1, out: ""}} = Rambo.run("printf")
   end
 
+  test "spawn error" do
+    assert {:error, %Rambo.SpawnError{reason: :enoent, kind: :not_found, program: "rambo-nope"}} =
+             Rambo.run("rambo-nope")
+
+    assert {:error, %Rambo.SpawnError{reason: :eacces}} = Rambo.run("./test")
+  end
+
+  test "terminated by signal" do
+    assert {:error, %{status: nil, signal: 9, core_dumped: false}} =
+             Rambo.run("/bin/sh", ["-c", "kill -KILL $$"])
+  end
+
+  test "protocol error" do
+    rambo = Mix.Tasks.Compile.Rambo.find_rambo()
+    port = Port.open({:spawn, rambo}, [:binary, :exit_status, {:packet, 4}])
+    Port.command(port, <<255>>)
+
+    assert_receive {^port, {:data, <<29, "unknown message 255">>}}
+    assert_receive {^port, {:exit_status, 0}}
+  end
+
+  test "handshake" do
+    rambo = Mix.Tasks.Compile.Rambo.find_rambo()
+    port = Port.open({:spawn, rambo}, [:binary, :exit_status, {:packet, 4}])
+    Port.command(port, <<30, 1::32, 0::64>>)
+
+    assert_receive {^port, {:data, <<30, 1::32, capabilities::64>>}}
+    assert capabilities > 0
+    Port.close(port)
+
+    # sessions shake hands once for themselves before any request
+    assert {:ok, session} = Rambo.open_session()
+    Port.close(session)
+  end
+
   test "arguments" do
     assert {:ok, %{out: "rambo\n"}} = Rambo.run("echo", "rambo")
     assert {:ok, %{out: "rambo"}} = Rambo.run("echo", ["-n", "rambo"])
   end
 
+  test "binary arguments" do
+    assert {:ok, %{out: <<0xE9, 0xFF>>}} = Rambo.run("printf", ["%s", <<0xE9, 0xFF>>])
+
+    env = %{"LATIN1" => <<0xE9>>}
+    assert {:ok, %{out: <<0xE9>>}} = Rambo.run("/bin/sh", ["-c", "printf %s $LATIN1"], env: env)
+  end
+
+  test "standard error modes" do
+    script = "echo 1; echo 2 >&2; echo 3"
+    assert {:ok, %{out: "1\n2\n3\n", err: ""}} =
+             Rambo.run("/bin/sh", ["-c", script], stderr: :merge)
+
+    assert {:ok, %{out: "1\n3\n", err: ""}} =
+             Rambo.run("/bin/sh", ["-c", script], stderr: :discard)
+  end
+
   test "environment variables" do
     env = %{"FOO" => "foo"}
     assert {:ok, %{out: "foo\n"}} = Rambo.run("/bin/sh", ["-c", "echo $FOO"], env: env)
@@ -28,11 +90,73 @@ defmodule RamboTest do
     assert {:ok, %{out: "rambo_test.exs\ntest_helper.exs\n"}} = Rambo.run("ls", cd: "test")
   end
 
+  test "pseudo-terminal" do
+    assert {:ok, %{out: "terminal\r\n"}} =
+             Rambo.run("/bin/sh", ["-c", "test -t 1 && echo terminal"], pty: true)
+
+    assert {:ok, %{out: "30 100\r\n"}} = Rambo.run("stty", "size", pty: {30, 100})
+    assert_raise ArgumentError, fn -> Rambo.run("stty", "size", pty: {65_536, 80}) end
+    assert_raise ArgumentError, fn -> Rambo.run("stty", "size", pty: {24, 0}) end
+    assert_raise ArgumentError, fn -> Rambo.resize(self(), 24, 65_536) end
+
+    assert {:ok, %{out: "error\r\n", err: ""}} =
+             Rambo.run("/bin/sh", ["-c", "echo error >&2"], pty: true)
+  end
+
+  test "resource limits" do
+    script = "while true; do :; done"
+    assert {:error, %Rambo{status: nil, signal: 24, limit_exceeded: :cpu}} =
+             Rambo.run("/bin/sh", ["-c", script], limits: [cpu: 1])
+
+    assert {:ok, %{out: "64\n"}} = Rambo.run("/bin/sh", ["-c", "ulimit -n"], limits: [nofile: 64])
+  end
+
+  test "resource usage" do
+    assert {:ok, %Rambo{usage: nil}} = Rambo.run("echo")
+
+    assert {:ok, %Rambo{usage: usage}} = Rambo.run("sleep", "0.2", usage: true)
+    assert usage.wall_time >= 200_000
+    assert usage.max_rss > 0
+
+    assert {:timeout, %Rambo{usage: %{wall_time: _}}} =
+             Rambo.run("sleep", "1", timeout: 100, usage: true)
+  end
+
+  test "user, group and umask" do
+    {uid, 0} = System.cmd("id", ["-u"])
+    {gid, 0} = System.cmd("id", ["-g"])
+    uid = uid |> String.trim() |> String.to_integer()
+    gid = gid |> String.trim() |> String.to_integer()
+
+    assert {:ok, %{out: out}} = Rambo.run("id", uid: uid, gid: gid)
+    assert out =~ "uid=#{uid}"
+    assert {:ok, %{out: "0027\n"}} = Rambo.run("/bin/sh", ["-c", "umask"], umask: 0o027)
+
+    # would wrap around to root
+    assert_raise ArgumentError, fn -> Rambo.run("id", uid: 0x1_0000_0000) end
+    assert_raise ArgumentError, fn -> Rambo.run("id", gid: -1) end
+    assert_raise ArgumentError, fn -> Rambo.run("id", groups: [0, 0x1_0000_0000]) end
+    assert_raise ArgumentError, fn -> Rambo.run("id", umask: 0o1000) end
+  end
+
   test "piping runs" do
     assert {:ok, %Rambo{}} = result = Rambo.run("echo", "rambo")
     assert {:ok, %{out: "rambo\n"}} = result |> Rambo.run("cat") |> Rambo.run("cat")
   end
 
+  test "pipeline" do
+    stages = [{"printf", "b\\na\\n"}, "sort", {"/bin/sh", ["-c", "cat; exit 3"]}]
+    assert {:error, %Rambo{out: "a\nb\n", status: 3, statuses: [0, 0, 3]}} =
+             Rambo.pipeline(stages)
+
+    stages = [{"/bin/sh", ["-c", "exit 2"]}, "cat"]
+    assert {:ok, %Rambo{status: 0, statuses: [2, 0]}} = Rambo.pipeline(stages)
+    assert {:error, %Rambo{status: 2}} = Rambo.pipeline(stages, pipefail: true)
+
+    stages = [{"/bin/sh", ["-c", "echo $RAMBO"], env: %{"RAMBO" => "john"}}, "cat"]
+    assert {:ok, %Rambo{out: "john\n"}} = Rambo.pipeline(stages)
+  end
+
   defmodule Bag do
     use Agent
 
@@ -56,11 +180,173 @@ defmodule RamboTest do
     assert [{:stdout, "rambo"}, {:stderr, _} | _] = Bag.look()
   end
 
+  test "timestamped logs" do
+    log = fn entry -> send(self(), entry) end
+    script = "echo 1; sleep 0.1; echo 2 >&2"
+    assert {:ok, %{out: "1\n", err: "2\n"}} =
+             Rambo.run("/bin/sh", ["-c", script], log: log, timestamps: true)
+
+    assert_received {:stdout, "1\n", %{seq: 0, at: first}}
+    assert_received {:stderr, "2\n", %{seq: 1, at: second}}
+    assert second - first >= 100_000_000
+  end
+
+  test "line framing" do
+    log = fn entry -> send(self(), entry) end
+    script = "printf ab; sleep 0.1; printf 'c\\nde\\nfghij'"
+    Rambo.run("/bin/sh", ["-c", script], log: log, framing: {:line, 4})
+
+    assert_received {:stdout, "abc\n"}
+    assert_received {:stdout, "de\n"}
+    assert_received {:stdout, "fghi"}
+    assert_received {:stdout, "j"}
+
+    # the newline right at the limit still ends the line
+    Rambo.run("printf", "abcd\\nefghi\\n", log: log, framing: {:line, 4})
+    assert_received {:stdout, "abcd\n"}
+    assert_received {:stdout, "efgh"}
+    assert_received {:stdout, "i\n"}
+
+    assert_raise ArgumentError, fn -> Rambo.run("echo", framing: {:line, 0x1_0000_0000}) end
+  end
+
+  test "output limits" do
+    assert {:ok, %Rambo{out: "y\ny\n", truncated: true}} =
+             Rambo.run("/bin/sh", ["-c", "yes | head -n 1000"], max_out: 4)
+
+    assert {:output_limit, %Rambo{out: "y\ny\n", truncated: true, killed_by: :sigkill}} =
+             Rambo.run("yes", max_out: {4, :kill})
+
+    assert {:ok, %Rambo{truncated: false}} = Rambo.run("echo", max_out: 100, max_err: 100)
+  end
+
+  test "server" do
+    {:ok, server} = Rambo.Server.start_link(size: 2)
+
+    tasks = for n <- 1..10, do: Task.async(fn -> Rambo.run("echo", "#{n}", server: server) end)
+    outs = for task <- tasks, do: elem(Task.await(task), 1).out
+    assert outs == for(n <- 1..10, do: "#{n}\n")
+
+    assert {:timeout, %Rambo{killed_by: :sigkill}} =
+             Rambo.run("sleep", "10", server: server, timeout: 100)
+
+    assert {:error, %Rambo.SpawnError{reason: :enoent}} = Rambo.run("rambo-nope", server: server)
+  end
+
+  test "start" do
+    {:ok, handle} = Rambo.start("/bin/sh", ["-c", "read name; echo hi $name >&2; exit 3"])
+    Rambo.write(handle, "rambo\n")
+    assert_receive {:rambo, ^handle, {:stderr, "hi rambo\n"}}
+    assert_receive {:rambo, ^handle, {:exit, 3}}
+
+    {:ok, handle} = Rambo.start("cat")
+    Rambo.kill(handle)
+    assert_receive {:rambo, ^handle, {:exit, {:killed, %Rambo{killed_by: :sigkill}}}}
+
+    {:ok, handle} = Rambo.start("sleep", "10", timeout: 100)
+    assert_receive {:rambo, ^handle, {:exit, {:timeout, %Rambo{}}}}, 1000
+
+    {:ok, handle} = Rambo.start("sleep", "10")
+    Rambo.signal(handle, :sigterm)
+    assert_receive {:rambo, ^handle, {:exit, {:signal, 15}}}
+
+    {:ok, handle} = Rambo.start("rambo-nope")
+    assert_receive {:rambo, ^handle, {:error, %Rambo.SpawnError{reason: :enoent}}}
+
+    # raised here rather than by the linked handle
+    assert_raise ArgumentError, fn -> Rambo.start("cat", timeout: -1) end
+  end
+
+  test "stream" do
+    lines = Rambo.stream("seq", ["1", "100000"], framing: :line)
+    assert Enum.take(lines, 3) == ["1\n", "2\n", "3\n"]
+    assert Enum.count(lines) == 100_000
+    refute_received {:rambo, _handle, _event}
+
+    assert Rambo.stream("cat", in: "rambo") |> Enum.join() == "rambo"
+    failing = Rambo.stream("/bin/sh", ["-c", "exit 3"])
+    assert_raise RuntimeError, ~r/status 3/, fn -> Enum.to_list(failing) end
+    assert_raise Rambo.SpawnError, fn -> Enum.to_list(Rambo.stream("rambo-nope")) end
+  end
+
+  test "credit" do
+    assert {:ok, %Rambo{out: out}} =
+             Rambo.run("seq", ["1", "10000"], credit: [frames: 1, bytes: 100])
+
+    assert out == Enum.map_join(1..10_000, &"#{&1}\n")
+
+    {:ok, handle} = Rambo.start("seq", ["1", "100000"], framing: :line, credit: [frames: 2])
+    assert_receive {:rambo, ^handle, {:stdout, "1\n"}}
+    assert_receive {:rambo, ^handle, {:stdout, "2\n"}}
+    refute_receive {:rambo, ^handle, {:stdout, _output}}, 200
+    Rambo.ack(handle, "1\n")
+    assert_receive {:rambo, ^handle, {:stdout, "3\n"}}
+    Rambo.kill(handle)
+    assert_receive {:rambo, ^handle, {:exit, {:killed, %Rambo{}}}}
+
+    assert_raise ArgumentError, fn -> Rambo.run("echo", credit: [frames: 0]) end
+    assert_raise ArgumentError, fn -> Rambo.run("echo", credit: [bytes: 0x1_0000_0000]) end
+  end
+
   test "kill" do
     task = Task.async(fn -> Rambo.run("cat") end)
     assert Process.alive?(task.pid)
 
     Rambo.kill(task.pid)
-    assert {:killed, %Rambo{status: nil}} = Task.await(task)
+    assert {:killed, %Rambo{status: nil, killed_by: :sigkill}} = Task.await(task)
+  end
+
+  test "kill process tree" do
+    script = "sleep 60 & echo $!; wait"
+    task = Task.async(fn -> Rambo.run("/bin/sh", ["-c", script], subreaper: true) end)
+    Process.sleep(500)
+
+    Rambo.kill(task.pid)
+    assert {:killed, %Rambo{out: out}} = Task.await(task)
+
+    Process.sleep(100)
+    assert {_, 1} = System.cmd("kill", ["-0", String.trim(out)], stderr_to_stdout: true)
+  end
+
+  test "kill grace" do
+    script = "trap 'echo cleaning up; exit 3' TERM; while true; do sleep 0.1; done"
+    task = Task.async(fn -> Rambo.run("/bin/sh", ["-c", script], kill_grace: 1000) end)
+    Process.sleep(500)
+
+    Rambo.kill(task.pid)
+    assert {:killed, %Rambo{status: 3, out: "cleaning up\n", killed_by: :sigterm}} =
+             Task.await(task)
+
+    script = "trap '' TERM; while true; do sleep 0.1; done"
+    result = Rambo.run("/bin/sh", ["-c", script], timeout: 500, kill_grace: 100)
+    assert {:timeout, %Rambo{status: nil, killed_by: :sigkill}} = result
+  end
+
+  test "timeout" do
+    assert {:timeout, %Rambo{killed_by: :sigkill}} = Rambo.run("sleep", "10", timeout: 100)
+    assert {:ok, %Rambo{}} = Rambo.run("echo", timeout: 1000)
+    refute_received :kill
+
+    assert_raise ArgumentError, fn -> Rambo.run("echo", timeout: 0x1_0000_0000) end
+    assert_raise ArgumentError, fn -> Rambo.run("echo", kill_grace: -1) end
+  end
+
+  test "signal" do
+    script = "trap 'echo terminated; exit 3' TERM; while true; do sleep 0.1; done"
+    task = Task.async(fn -> Rambo.run("/bin/sh", ["-c", script]) end)
+    Process.sleep(500)
+
+    Rambo.signal(task.pid, :sigterm)
+    assert {:error, %Rambo{status: 3, out: "terminated\n"}} = Task.await(task)
+
+    assert_raise ArgumentError, fn -> Rambo.signal(self(), :sigbogus) end
+  end
+
+  test "other messages are left alone" do
+    send(self(), {:signal, :sigterm})
+    send(self(), {:ack, 1})
+    assert {:ok, %Rambo{out: "rambo\n"}} = Rambo.run("echo", "rambo")
+    assert_received {:signal, :sigterm}
+    assert_received {:ack, 1}
   end
 end